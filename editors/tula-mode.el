@@ -44,7 +44,7 @@
 
 (eval-and-compile
   (defconst tula-keywords
-    '("if" "for" "case" "run" "trace" "in" "let")))
+    '("if" "for" "case" "run" "trace" "in" "let" "halt" "include" "test" "expect")))
 
 (defconst tula-highlights
   `((,(regexp-opt tula-keywords 'symbols) . font-lock-keyword-face)))
@@ -59,6 +59,10 @@
 ;;;###autoload
 (add-to-list 'auto-mode-alist '("\\.tula\\'" . tula-mode))
 
+;;;###autoload
+(with-eval-after-load 'eglot
+  (add-to-list 'eglot-server-programs '(tula-mode "tula" "lsp")))
+
 (provide 'tula-mode)
 
 ;;; tula-mode.el ends here
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+use super::lexer::{Lexer, Symbol};
+use super::expr::{Expr, Atom};
+use super::set_expr::{SetExpr, Sets};
+use super::dispatch::{Dispatch, bindings_in_scope};
+use super::diagnostics::{Code, Diagnostic, Fallible};
+use super::{Result, Scope, Quantifier, Program, Run, Machine, Transition};
+
+/// A pattern with its own Universal Quantifiers, like the input of a case.
+#[derive(Debug, Clone)]
+pub struct Pattern<'nsa> {
+    pub vars: Vec<(Symbol<'nsa>, SetExpr<'nsa>)>,
+    pub expr: Expr<'nsa>,
+}
+
+impl<'nsa> Pattern<'nsa> {
+    /// Parses `[for <vars> in <set>]* <expr>`.
+    pub fn parse(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
+        let mut vars = vec![];
+        while let Some(symbol) = lexer.peek_symbol() {
+            if symbol.name != "for" {
+                break;
+            }
+            lexer.next_symbol();
+            let mut names = vec![];
+            while let Some(symbol) = lexer.peek_symbol() {
+                if symbol.name == "in" {
+                    break;
+                }
+                match Atom::from_symbol(lexer.parse_symbol()?)? {
+                    Atom::Symbol(name) => names.push(name),
+                    atom => {
+                        lexer.diagnostics.report(Diagnostic::error(Code::TypeMismatch, *atom.loc(), format!("{human} may not be used as variable names", human = atom.human())));
+                        return Err(())
+                    }
+                }
+            }
+            let _ = lexer.expect_symbols(&["in"])?;
+            let set = SetExpr::parse(lexer, sets)?;
+            for name in names {
+                vars.push((name, set.clone()));
+            }
+        }
+        let expr = Expr::parse(lexer)?;
+        let expr = lexer.diagnostics.report_err(expr.force_evals())?;
+        let pattern = Pattern{vars, expr};
+        if let Some((var, _)) = pattern.vars.iter().find(|(var, _)| pattern.expr.uses_var(var).is_none()) {
+            lexer.diagnostics.report(Diagnostic::error(Code::UnusedVariable, var.loc, format!("variable {var} is not used in the pattern")));
+            return Err(())
+        }
+        Ok(pattern)
+    }
+
+    pub fn matches(&self, sets: &Sets<'nsa>, value: &Expr<'nsa>) -> bool {
+        let scope: Scope<'nsa> = self.vars.iter().map(|(var, set)| (*var, Quantifier{pattern: Expr::Atom(Atom::Symbol(*var)), set: set.clone()})).collect();
+        let mut bindings = HashMap::new();
+        self.expr.pattern_match(value, &scope, &mut bindings) && bindings_in_scope(&bindings, &scope, sets)
+    }
+}
+
+impl<'nsa> fmt::Display for Pattern<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (var, set) in &self.vars {
+            write!(f, "for {var} in {set} ")?;
+        }
+        write!(f, "{expr}", expr = self.expr)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Breakpoint<'nsa> {
+    /// The Machine is in a State that matches the pattern.
+    State(Pattern<'nsa>),
+    /// The value under the head matches the pattern.
+    Read(Pattern<'nsa>),
+    /// The value of the cell at the index matches the pattern.
+    Cell {
+        index: i32,
+        pattern: Pattern<'nsa>,
+    },
+    /// The next step is going to be performed by the case located at the row of the file.
+    Case {
+        file_path: Option<String>,
+        row: usize,
+    },
+}
+
+impl<'nsa> fmt::Display for Breakpoint<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::State(pattern) => write!(f, "state {pattern}"),
+            Self::Read(pattern) => write!(f, "read {pattern}"),
+            Self::Cell{index, pattern} => write!(f, "cell {index} {pattern}"),
+            Self::Case{file_path: Some(file_path), row} => write!(f, "case {file_path}:{row}"),
+            Self::Case{file_path: None, row} => write!(f, "case {row}"),
+        }
+    }
+}
+
+impl<'nsa> Breakpoint<'nsa> {
+    /// Parses one of
+    /// - `state <pattern>`
+    /// - `read <pattern>`
+    /// - `cell <index> <pattern>`
+    /// - `case [<file>:]<row>`
+    pub fn parse(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
+        let kind = lexer.expect_symbols(&["state", "read", "cell", "case"])?;
+        let breakpoint = match kind.name {
+            "state" => Self::State(Pattern::parse(lexer, sets)?),
+            "read" => Self::Read(Pattern::parse(lexer, sets)?),
+            "cell" => {
+                let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+                let index = lexer.diagnostics.report_err(atom.expect_integer())?;
+                let Ok(index) = i32::try_from(index) else {
+                    lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, kind.loc, format!("cell index {index} is out of bounds")));
+                    return Err(())
+                };
+                Self::Cell{index, pattern: Pattern::parse(lexer, sets)?}
+            }
+            "case" => {
+                let location = lexer.parse_symbol()?;
+                let (file_path, row) = match location.name.rsplit_once(':') {
+                    Some((file_path, row)) => (Some(file_path.to_string()), row),
+                    None => (None, location.name),
+                };
+                let Ok(row) = row.parse::<usize>() else {
+                    lexer.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, location.loc, format!("expected [<file>:]<row> of a case but got {location}")));
+                    return Err(())
+                };
+                Self::Case{file_path, row}
+            }
+            _ => unreachable!(),
+        };
+        if let Some(symbol) = lexer.next_symbol() {
+            lexer.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, symbol.loc, format!("unexpected {symbol} after the breakpoint")));
+            return Err(())
+        }
+        Ok(breakpoint)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// The requested amount of steps was performed.
+    Done,
+    /// The Machine halted.
+    Halted,
+    /// Stepping back reached the initial configuration of the Machine.
+    Start,
+    /// The breakpoint with the index was hit.
+    Breakpoint(usize),
+}
+
+/// Debugging session of a single run of the program.
+pub struct Debugger<'nsa> {
+    dispatch: Dispatch<'nsa>,
+    sets: Sets<'nsa>,
+    pub machine: Machine<'nsa>,
+    pub breakpoints: Vec<Breakpoint<'nsa>>,
+    /// The last step the Machine performed.
+    pub last: Option<Transition<'nsa>>,
+}
+
+impl<'nsa> Debugger<'nsa> {
+    /// Expects the program to be already sanity checked.
+    pub fn new(program: &Program<'nsa>, run: &Run<'nsa>) -> Self {
+        Self {
+            dispatch: program.dispatch(),
+            sets: program.sets.clone(),
+            machine: {
+                let mut machine = Machine::from_run(run);
+                machine.record_undo();
+                machine
+            },
+            breakpoints: vec![],
+            last: None,
+        }
+    }
+
+    pub fn sets(&self) -> &Sets<'nsa> {
+        &self.sets
+    }
+
+    fn breakpoint_hit(&self, breakpoint: &Breakpoint<'nsa>) -> Fallible<'nsa, bool> {
+        let machine = &self.machine;
+        match breakpoint {
+            Breakpoint::State(pattern) => Ok(pattern.matches(&self.sets, &machine.state)),
+            Breakpoint::Read(pattern) => Ok(pattern.matches(&self.sets, &machine.tape[machine.head])),
+            Breakpoint::Cell{index, pattern} => Ok(pattern.matches(&self.sets, &machine.tape[*index])),
+            Breakpoint::Case{file_path, row} => {
+                if machine.halt {
+                    return Ok(false)
+                }
+                let Some(outcome) = self.dispatch.match_next_case(&machine.state, &machine.tape[machine.head])? else {
+                    return Ok(false)
+                };
+                let loc = outcome.keyword.loc;
+                Ok(loc.row == *row && file_path.as_ref().is_none_or(|file_path| file_path == loc.file_path))
+            }
+        }
+    }
+
+    fn hit_breakpoint(&self) -> Fallible<'nsa, Option<usize>> {
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            if self.breakpoint_hit(breakpoint)? {
+                return Ok(Some(i))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Performs up to `limit` steps (or until the Machine halts if the `limit` is not provided)
+    /// stopping at the first breakpoint that is hit after a step. `on_step` is called after each
+    /// step, which is useful for reacting on the `!` actions.
+    pub fn resume<F>(&mut self, limit: Option<usize>, on_step: &mut F) -> Fallible<'nsa, Stop> where F: FnMut(&Machine<'nsa>, &Transition<'nsa>) {
+        let mut steps = 0;
+        loop {
+            if limit.is_some_and(|limit| steps >= limit) {
+                return Ok(Stop::Done)
+            }
+            let Some(transition) = self.machine.next(&self.dispatch)? else {
+                return Ok(Stop::Halted)
+            };
+            on_step(&self.machine, &transition);
+            self.last = Some(transition);
+            steps += 1;
+            if let Some(i) = self.hit_breakpoint()? {
+                return Ok(Stop::Breakpoint(i))
+            }
+        }
+    }
+
+    /// Reverts up to `limit` steps (or all of them if the `limit` is not provided) stopping at
+    /// the first breakpoint that is hit after reverting a step.
+    pub fn resume_back(&mut self, limit: Option<usize>) -> Fallible<'nsa, Stop> {
+        let mut steps = 0;
+        let stop = loop {
+            if limit.is_some_and(|limit| steps >= limit) {
+                break Stop::Done
+            }
+            if !self.machine.step_back() {
+                break Stop::Start
+            }
+            steps += 1;
+            if let Some(i) = self.hit_breakpoint()? {
+                break Stop::Breakpoint(i)
+            }
+        };
+        self.redo_last()?;
+        Ok(stop)
+    }
+
+    /// Brings the Machine to the configuration after the given amount of steps ignoring the
+    /// breakpoints. Steps that were already performed are reverted instead of re-running the
+    /// program from the start.
+    pub fn goto<F>(&mut self, step: usize, on_step: &mut F) -> Fallible<'nsa, Stop> where F: FnMut(&Machine<'nsa>, &Transition<'nsa>) {
+        if step < self.machine.steps {
+            while self.machine.steps > step {
+                self.machine.step_back();
+            }
+            self.redo_last()?;
+            return Ok(Stop::Done)
+        }
+        while self.machine.steps < step {
+            let Some(transition) = self.machine.next(&self.dispatch)? else {
+                return Ok(Stop::Halted)
+            };
+            on_step(&self.machine, &transition);
+            self.last = Some(transition);
+        }
+        Ok(Stop::Done)
+    }
+
+    /// The undo log does not keep the whole [`Transition`]s around, so the last one is
+    /// recovered by reverting it and performing it again.
+    fn redo_last(&mut self) -> Fallible<'nsa, ()> {
+        self.last = if self.machine.step_back() {
+            self.machine.next(&self.dispatch)?
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+use std::fmt::{self, Write};
+use std::result;
+use std::collections::HashMap;
+use super::lexer::{Lexer, Loc};
+use super::Result;
+use super::json;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "ERROR"),
+            Self::Warning => write!(f, "WARNING"),
+        }
+    }
+}
+
+impl Severity {
+    fn json_name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+/// How the [`Diagnostics`] are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MessageFormat {
+    /// For humans, with the snippets of the source.
+    #[default]
+    Text,
+    /// One JSON object per line, for editors and CI.
+    Json,
+}
+
+impl MessageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Stable identifier of a kind of problem. `tula explain <code>` prints the explanation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    UnexpectedSymbol,
+    TypeMismatch,
+    UnknownSet,
+    Redefinition,
+    RepeatingValues,
+    UnusedVariable,
+    Shadowing,
+    EvalInPattern,
+    InvalidTape,
+    Evaluation,
+    InfiniteSet,
+    Stuck,
+    DidNotHalt,
+    Overlap,
+    DeadEnd,
+    Include,
+    DebuggerCommand,
+    CommandLine,
+}
+
+impl Code {
+    pub const ALL: &[Code] = &[
+        Code::UnexpectedSymbol,
+        Code::TypeMismatch,
+        Code::UnknownSet,
+        Code::Redefinition,
+        Code::RepeatingValues,
+        Code::UnusedVariable,
+        Code::Shadowing,
+        Code::EvalInPattern,
+        Code::InvalidTape,
+        Code::Evaluation,
+        Code::InfiniteSet,
+        Code::Stuck,
+        Code::DidNotHalt,
+        Code::Overlap,
+        Code::DeadEnd,
+        Code::Include,
+        Code::DebuggerCommand,
+        Code::CommandLine,
+    ];
+
+    /// The codes are never reused, so new kinds of problems must be appended to the end.
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::UnexpectedSymbol => "T0001",
+            Self::TypeMismatch     => "T0002",
+            Self::UnknownSet       => "T0003",
+            Self::Redefinition     => "T0004",
+            Self::RepeatingValues  => "T0005",
+            Self::UnusedVariable   => "T0006",
+            Self::Shadowing        => "T0007",
+            Self::EvalInPattern    => "T0008",
+            Self::InvalidTape      => "T0009",
+            Self::Evaluation       => "T0010",
+            Self::InfiniteSet      => "T0011",
+            Self::Stuck            => "T0012",
+            Self::DidNotHalt       => "T0013",
+            Self::Overlap          => "T0014",
+            Self::DeadEnd          => "T0015",
+            Self::Include          => "T0016",
+            Self::DebuggerCommand  => "T0017",
+            Self::CommandLine      => "T0018",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|code| code.id().eq_ignore_ascii_case(id))
+    }
+
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Self::UnexpectedSymbol => "\
+The parser found a symbol that can't appear at this place of the program, or
+the input ended in the middle of a construct.
+
+    case I 0 1 -> I Kek
+
+The case is complete after `I`, so `Kek` is taken as the beginning of the next
+statement, but it's not a keyword. The top level statements start with one of
+`include`, `let`, `case`, `for`, `halt`, `run`, `trace` or `test`.",
+            Self::TypeMismatch => "\
+A value of one kind was used where a value of another kind is expected. For
+example, a set name or a variable name must be a symbol, the operands of an
+Integer operation must be Integers, a set expression may not be a number.
+
+    let 69 { a b c }
+    [1 + 'one']",
+            Self::UnknownSet => "\
+The set expression refers to a set that is not defined. Sets must be defined
+with `let` before they are used.
+
+    for a in Trits
+    case I a a -> I",
+            Self::Redefinition => "\
+A set with the same name is already defined. The magical sets (`Integer`,
+`Real`, `String`, `Boolean`, `Char`, `Byte`) may not be redefined either.
+
+    let Bits { 0 1 }
+    let Bits { 1 0 }",
+            Self::RepeatingValues => "\
+An anonymous set lists the same value more than once.
+
+    let Bits { 0 1 0 }",
+            Self::UnusedVariable => "\
+A variable of a Universal Quantifier is not used in the input (State and Read)
+of the case, the halt state or the pattern it quantifies. Such a variable can't
+be inferred when the case is matched.
+
+    for a b in { 1 2 3 }
+    case I a 0 -> I",
+            Self::Shadowing => "\
+A variable shadows another variable of an enclosing Universal Quantifier, or
+the same pattern binds the variable more than once.
+
+    for a in Bits
+    for a in Bits
+    case I a 0 -> I",
+            Self::EvalInPattern => "\
+Eval Expressions are computed when the case fires, so they may not be used in
+the places that are matched against the values: the State and Read of a case,
+halt states and the patterns of Universal Quantifiers.
+
+    for n in Integer
+    case I [n + 1] 0 -> I",
+            Self::InvalidTape => "\
+The tape or the configuration of a run is malformed: the tape is empty, the
+position of the head is marked more than once or points outside of the tape,
+the amount of steps is negative.
+
+    run I { }",
+            Self::Evaluation => "\
+An Eval Expression could not be computed: division by zero, an integer that
+does not fit into a byte, an index out of bounds of a string, an unsupported
+operation, etc.
+
+    case I 0 [1 / 0] -> I",
+            Self::InfiniteSet => "\
+The set can't be expanded into the list of its elements because it's too big.
+`Integer`, `Real`, `String`, `Char` and the ranges of more than 65536 integers
+can only be used for type checking and pattern matching, but `tula expand` has
+to list every case explicitly.",
+            Self::Stuck => "\
+The program declares its halt states with `halt`, but the machine reached a
+state that has no case for the value under the head and is not declared as a
+halt state.",
+            Self::DidNotHalt => "\
+The run reached the limit set by `--max-steps` or `--timeout` before the
+machine halted.",
+            Self::Overlap => "\
+Several cases may match the same State and Read. The first one in the source
+takes precedence, so the other one may never fire for some inputs.
+`tula check` warns about them unless `--allow-overlaps` is passed, while
+`tula run` and `tula expand` report them only with `--warn-overlaps` or
+`--deny-overlaps`.
+
+    for a in Integer
+    case I a 0 -> I
+    case I 5 0 -> I",
+            Self::DeadEnd => "\
+The program declares its halt states with `halt`, but a case may switch the
+machine to a state that has no cases and is not declared as a halt state.",
+            Self::Include => "\
+The included file could not be read, or files include each other in a cycle.",
+            Self::DebuggerCommand => "\
+The command of `tula debug` is unknown or its arguments are invalid. Type
+`help` in the debugger for the list of commands.",
+            Self::CommandLine => "\
+The command line of `tula` is invalid: the command or a flag is unknown, a flag
+has no value or a malformed one, the input file is missing or can't be read.
+`tula help <command>` prints the usage of the command.",
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{id}", id = self.id())
+    }
+}
+
+/// A single problem found in the program together with the notes that explain it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic<'nsa> {
+    pub severity: Severity,
+    pub code: Code,
+    /// `None` for the problems that are not located in any source, like an invalid command line.
+    pub loc: Option<Loc<'nsa>>,
+    pub message: String,
+    pub notes: Vec<(Loc<'nsa>, String)>,
+}
+
+impl<'nsa> Diagnostic<'nsa> {
+    pub fn error(code: Code, loc: Loc<'nsa>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            loc: Some(loc),
+            message: message.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(code: Code, loc: Loc<'nsa>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(code, loc, message)
+        }
+    }
+
+    pub fn global(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            loc: None,
+            message: message.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn note(mut self, loc: Loc<'nsa>, message: impl Into<String>) -> Self {
+        self.notes.push((loc, message.into()));
+        self
+    }
+}
+
+impl<'nsa> fmt::Display for Diagnostic<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Diagnostic{severity, code, loc, message, notes} = self;
+        if let Some(loc) = loc {
+            write!(f, "{loc}: ")?;
+        }
+        write!(f, "{severity}[{code}]: {message}")?;
+        for (loc, message) in notes {
+            write!(f, "\n{loc}: NOTE: {message}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of the operations that are not tied to a [`Diagnostics`] collector (like
+/// evaluating expressions), so the caller decides where the problem is reported.
+pub type Fallible<'nsa, T> = result::Result<T, Diagnostic<'nsa>>;
+
+/// Collects all the diagnostics reported while processing the program. Each diagnostic is
+/// printed the moment it's reported, so the output is interleaved with the rest of the output
+/// of the program the same way it always was.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics<'nsa> {
+    pub reported: Vec<Diagnostic<'nsa>>,
+    pub format: MessageFormat,
+    /// Only collect the diagnostics without printing them, like the Language Server does.
+    pub silent: bool,
+    /// Sources of the files the diagnostics may refer to, for rendering the snippets.
+    sources: HashMap<&'nsa str, &'nsa str>,
+}
+
+impl<'nsa> Diagnostics<'nsa> {
+    pub fn add_source(&mut self, file_path: &'nsa str, source: &'nsa str) {
+        self.sources.insert(file_path, source);
+    }
+
+    pub fn report(&mut self, diagnostic: Diagnostic<'nsa>) {
+        if self.silent {
+            self.reported.push(diagnostic);
+            return
+        }
+        match self.format {
+            MessageFormat::Text => eprintln!("{rendered}", rendered = self.render(&diagnostic)),
+            MessageFormat::Json => eprintln!("{rendered}", rendered = self.render_json(&diagnostic)),
+        }
+        self.reported.push(diagnostic);
+    }
+
+    /// Reports the error of the result if there is any.
+    pub fn report_err<T>(&mut self, result: Fallible<'nsa, T>) -> Result<T> {
+        result.map_err(|diagnostic| self.report(diagnostic))
+    }
+
+    pub fn extend(&mut self, other: Diagnostics<'nsa>) {
+        self.reported.extend(other.reported);
+        self.sources.extend(other.sources);
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.reported.iter().filter(|diagnostic| diagnostic.severity == severity).count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    /// `N errors, M warnings` or `None` if nothing was reported or the diagnostics are not
+    /// meant for humans.
+    pub fn summary(&self) -> Option<String> {
+        if self.reported.is_empty() || self.format != MessageFormat::Text {
+            return None
+        }
+        let plural = |n: usize, what: &str| if n == 1 { format!("{n} {what}") } else { format!("{n} {what}s") };
+        Some(format!("{errors}, {warnings}", errors = plural(self.errors(), "error"), warnings = plural(self.warnings(), "warning")))
+    }
+
+    /// The line of the source the location points at.
+    pub fn line(&self, loc: &Loc<'nsa>) -> Option<&'nsa str> {
+        self.sources.get(loc.file_path)?.lines().nth(loc.row.checked_sub(1)?)
+    }
+
+    /// The amount of characters of the symbol that starts at the location. At least 1, so
+    /// the end of the input can be pointed at too.
+    pub fn span_length(&self, loc: &Loc<'nsa>) -> usize {
+        let Some(line) = self.line(loc) else {
+            return 1
+        };
+        let Some((i, _)) = line.char_indices().nth(loc.col - 1) else {
+            return 1
+        };
+        Lexer::new(&line[i..], loc.file_path).next_symbol().map(|symbol| symbol.name.chars().count()).unwrap_or(1).max(1)
+    }
+
+    fn render_json_span(&self, buffer: &mut String, loc: &Loc<'nsa>) {
+        let _ = write!(buffer, "\"file\":{file},\"row\":{row},\"col\":{col},\"length\":{length}",
+                       file = json::Str(loc.file_path), row = loc.row, col = loc.col, length = self.span_length(loc));
+    }
+
+    /// Renders the diagnostic as a single line JSON object. The notes become the secondary
+    /// spans labeled with their messages.
+    pub fn render_json(&self, diagnostic: &Diagnostic<'nsa>) -> String {
+        let Diagnostic{severity, code, loc, message, notes} = diagnostic;
+        let mut buffer = String::new();
+        let _ = write!(&mut buffer, "{{\"severity\":{severity},\"code\":{code},\"message\":{message},\"primary\":",
+                       severity = json::Str(severity.json_name()), code = json::Str(code.id()), message = json::Str(message));
+        match loc {
+            Some(loc) => {
+                buffer.push('{');
+                self.render_json_span(&mut buffer, loc);
+                buffer.push('}');
+            }
+            None => buffer.push_str("null"),
+        }
+        buffer.push_str(",\"secondary\":[");
+        for (i, (loc, label)) in notes.iter().enumerate() {
+            if i > 0 {
+                buffer.push(',');
+            }
+            buffer.push('{');
+            self.render_json_span(&mut buffer, loc);
+            let _ = write!(&mut buffer, ",\"label\":{label}}}", label = json::Str(label));
+        }
+        buffer.push_str("]}");
+        buffer
+    }
+
+    /// Renders the source line of the location underlining the symbol with `mark`.
+    fn render_snippet(&self, buffer: &mut String, loc: &Loc<'nsa>, gutter: usize, mark: char, label: &str, same_line: bool) -> bool {
+        let Some(line) = self.line(loc) else {
+            return false
+        };
+        if !same_line {
+            let _ = writeln!(buffer, "{row:>gutter$} | {line}", row = loc.row);
+        }
+        // Tabs are kept as they are so the marks line up with the line above them.
+        let pad: String = line.chars().take(loc.col - 1).map(|x| if x == '\t' { '\t' } else { ' ' }).collect();
+        let marks = mark.to_string().repeat(self.span_length(loc));
+        let _ = write!(buffer, "{empty:>gutter$} | {pad}{marks}", empty = "");
+        if !label.is_empty() {
+            let _ = write!(buffer, " {label}");
+        }
+        buffer.push('\n');
+        true
+    }
+
+    /// Renders the diagnostic with the source lines it refers to. The notes located in the
+    /// same file are rendered as secondary labels of the snippet, the rest of them as separate
+    /// locations.
+    pub fn render(&self, diagnostic: &Diagnostic<'nsa>) -> String {
+        let Diagnostic{severity, code, loc, message, notes} = diagnostic;
+        let gutter = notes.iter().map(|(loc, _)| loc.row).chain(loc.map(|loc| loc.row)).max().unwrap_or(0).to_string().len();
+        let mut buffer = String::new();
+        // The file and the row of the last rendered snippet.
+        let mut last = None;
+        match loc {
+            Some(loc) => {
+                let _ = writeln!(&mut buffer, "{loc}: {severity}[{code}]: {message}");
+                if self.render_snippet(&mut buffer, loc, gutter, '^', "", false) {
+                    last = Some((loc.file_path, loc.row));
+                }
+            }
+            None => {
+                let _ = writeln!(&mut buffer, "{severity}[{code}]: {message}");
+            }
+        }
+        for (note_loc, note) in notes {
+            if let Some((file_path, row)) = last {
+                if file_path == note_loc.file_path && self.render_snippet(&mut buffer, note_loc, gutter, '-', note, row == note_loc.row) {
+                    last = Some((note_loc.file_path, note_loc.row));
+                    continue
+                }
+            }
+            let _ = writeln!(&mut buffer, "{note_loc}: NOTE: {note}");
+            last = None;
+            if self.render_snippet(&mut buffer, note_loc, gutter, '-', "", false) {
+                last = Some((note_loc.file_path, note_loc.row));
+            }
+        }
+        buffer.pop();
+        buffer
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use super::lexer::Symbol;
+use super::expr::{Expr, Atom};
+use super::set_expr::Sets;
+use super::diagnostics::Fallible;
+use super::{Scope, Quantifier, Case, Statement};
+
+/// The outcome of a successfully matched case.
+#[derive(Debug, Clone)]
+pub struct Outcome<'nsa> {
+    /// The `case` keyword of the matched case.
+    pub keyword: Symbol<'nsa>,
+    /// The values of the variables of the matched case.
+    pub bindings: HashMap<Symbol<'nsa>, Expr<'nsa>>,
+    pub write: Expr<'nsa>,
+    pub step: Expr<'nsa>,
+    pub next: Expr<'nsa>,
+}
+
+/// Coarse shape of an expression used for indexing the cases.
+///
+/// A pattern is indexed by the most specific key it is guaranteed to have, a value is looked up
+/// by all the keys it may match against. Real numbers are not indexed by value because their
+/// equality does not agree with their hashing (think `0.0` and `-0.0`).
+#[derive(Debug, Clone, PartialEq, Hash)]
+enum Key<'nsa> {
+    Any,
+    Atom(Atom<'nsa>),
+    Real,
+    Tuple {
+        arity: usize,
+    },
+    TupleHead {
+        arity: usize,
+        head: Atom<'nsa>,
+    },
+}
+
+// Keys never hold Real atoms, so the equality is reflexive.
+impl<'nsa> Eq for Key<'nsa> {}
+
+impl<'nsa> Key<'nsa> {
+    fn atom(atom: &Atom<'nsa>) -> Self {
+        match atom {
+            Atom::Real{..} => Key::Real,
+            _ => Key::Atom(atom.clone()),
+        }
+    }
+
+    fn of_pattern(pattern: &Expr<'nsa>, scope: &Scope<'nsa>) -> Self {
+        match pattern {
+            Expr::Atom(Atom::Symbol(symbol)) if scope.contains_key(symbol) => Key::Any,
+            Expr::Atom(atom) => Key::atom(atom),
+            Expr::Tuple{elements, ..} => match elements.first() {
+                Some(Expr::Atom(Atom::Symbol(symbol))) if scope.contains_key(symbol) => Key::Tuple{arity: elements.len()},
+                Some(Expr::Atom(Atom::Real{..})) => Key::Tuple{arity: elements.len()},
+                Some(Expr::Atom(head)) => Key::TupleHead{arity: elements.len(), head: head.clone()},
+                _ => Key::Tuple{arity: elements.len()},
+            }
+            Expr::Eval{..} => Key::Any,
+        }
+    }
+
+    fn of_value(value: &Expr<'nsa>) -> Vec<Self> {
+        match value {
+            Expr::Atom(atom) => vec![Key::atom(atom), Key::Any],
+            Expr::Tuple{elements, ..} => {
+                let arity = elements.len();
+                match elements.first() {
+                    Some(Expr::Atom(head @ (Atom::Symbol(_) | Atom::Integer{..} | Atom::String{..}))) => {
+                        vec![Key::TupleHead{arity, head: head.clone()}, Key::Tuple{arity}, Key::Any]
+                    }
+                    _ => vec![Key::Tuple{arity}, Key::Any],
+                }
+            }
+            Expr::Eval{..} => vec![Key::Any],
+        }
+    }
+}
+
+/// Checks that all the variables bound by pattern matching belong to the sets of their quantifiers.
+/// Variables destructured from a tuple are checked together as the whole tuple.
+pub(crate) fn bindings_in_scope<'nsa>(bindings: &HashMap<Symbol<'nsa>, Expr<'nsa>>, scope: &Scope<'nsa>, sets: &Sets<'nsa>) -> bool {
+    for (var, Quantifier{pattern, set}) in scope.iter() {
+        if let Some(value) = bindings.get(var) {
+            let value = match pattern {
+                Expr::Atom(_) => value.clone(),
+                _ => pattern.substitute_bindings(bindings),
+            };
+            if !set.contains(sets, &value) {
+                return false
+            }
+        } else {
+            unreachable!("Unused variable is found at runtime. Sanity check was not performed before execution.");
+        }
+    }
+    true
+}
+
+/// The elements of the sets of the variables destructured from a tuple that are not bound by
+/// matching the input. Expanded once when the case is compiled.
+type Destructured<'nsa> = HashMap<Symbol<'nsa>, Vec<Expr<'nsa>>>;
+
+/// Expands the sets of the variables of the scope that the input `patterns` do not bind.
+/// Expects the scope to be sanity checked, which makes sure such sets are expandable.
+fn destructured<'nsa>(patterns: &[&Expr<'nsa>], scope: &Scope<'nsa>, sets: &Sets<'nsa>) -> Destructured<'nsa> {
+    scope.iter()
+        .filter(|(var, _)| patterns.iter().all(|pattern| pattern.uses_var(var).is_none()))
+        .map(|(var, Quantifier{set, ..})| {
+            let elements = set.expand(sets).expect("Destructured variable is bound by a set that can't be expanded. Sanity check was not performed before execution.");
+            (*var, elements)
+        })
+        .collect()
+}
+
+/// Binds the variables destructured from a tuple that were not bound by matching the input by
+/// looking up the first element of the set (in the order of its expansion) that matches the
+/// tuple with the already bound variables. Returns `false` if there is no such element.
+fn destructure_bindings<'nsa>(bindings: &mut HashMap<Symbol<'nsa>, Expr<'nsa>>, scope: &Scope<'nsa>, destructured: &Destructured<'nsa>) -> bool {
+    for (var, elements) in destructured {
+        if bindings.contains_key(var) {
+            continue
+        }
+        let pattern = &scope[var].pattern;
+        let mut found = false;
+        for element in elements {
+            let mut bound = bindings.clone();
+            if pattern.pattern_match(element, scope, &mut bound) {
+                *bindings = bound;
+                found = true;
+                break
+            }
+        }
+        if !found {
+            return false
+        }
+    }
+    true
+}
+
+/// A case with all of its Universal Quantifiers collected into a flat scope.
+#[derive(Debug, Clone)]
+struct CompiledCase<'nsa> {
+    case: Case<'nsa>,
+    scope: Scope<'nsa>,
+    destructured: Destructured<'nsa>,
+}
+
+impl<'nsa> CompiledCase<'nsa> {
+    fn try_match(&self, sets: &Sets<'nsa>, state: &Expr<'nsa>, read: &Expr<'nsa>) -> Fallible<'nsa, Option<Outcome<'nsa>>> {
+        let mut bindings = HashMap::new();
+
+        if !self.case.state.pattern_match(state, &self.scope, &mut bindings) {
+            return Ok(None)
+        }
+        if !self.case.read.pattern_match(read, &self.scope, &mut bindings) {
+            return Ok(None)
+        }
+
+        if !destructure_bindings(&mut bindings, &self.scope, &self.destructured) || !bindings_in_scope(&bindings, &self.scope, sets) {
+            return Ok(None)
+        }
+
+        let write = self.case.write.substitute_bindings(&bindings).force_evals()?;
+        let step = self.case.step.substitute_bindings(&bindings).force_evals()?;
+        let next = self.case.next.substitute_bindings(&bindings).force_evals()?;
+        Ok(Some(Outcome {
+            keyword: self.case.keyword,
+            bindings,
+            write,
+            step,
+            next,
+        }))
+    }
+}
+
+/// Precompiled index of all the cases of a program keyed by the shape of their State and Read
+/// patterns. Built once per program so each step of the Machine only tests the cases that may
+/// actually match, preserving the "first case in the source order wins" semantics.
+#[derive(Debug, Clone)]
+pub struct Dispatch<'nsa> {
+    sets: Sets<'nsa>,
+    cases: Vec<CompiledCase<'nsa>>,
+    index: HashMap<(Key<'nsa>, Key<'nsa>), Vec<usize>>,
+    halts: Vec<(Expr<'nsa>, Scope<'nsa>, Destructured<'nsa>)>,
+}
+
+impl<'nsa> Dispatch<'nsa> {
+    /// Expects the statements to be already sanity checked.
+    pub fn new(sets: &Sets<'nsa>, statements: &[Statement<'nsa>]) -> Self {
+        let mut dispatch = Dispatch {
+            sets: sets.clone(),
+            cases: vec![],
+            index: HashMap::new(),
+            halts: vec![],
+        };
+        let mut scope = Scope::new();
+        for statement in statements {
+            statement.visit_cases(&mut scope, &mut |case, scope| dispatch.compile(case, scope));
+            statement.visit_halts(&mut scope, &mut |state, scope| {
+                let destructured = destructured(&[state], scope, sets);
+                dispatch.halts.push((state.clone(), scope.clone(), destructured))
+            });
+        }
+        dispatch
+    }
+
+    fn compile(&mut self, case: &Case<'nsa>, scope: &Scope<'nsa>) {
+        let key = (Key::of_pattern(&case.state, scope), Key::of_pattern(&case.read, scope));
+        self.index.entry(key).or_default().push(self.cases.len());
+        self.cases.push(CompiledCase {
+            case: case.clone(),
+            scope: scope.clone(),
+            destructured: destructured(&[&case.state, &case.read], scope, &self.sets),
+        });
+    }
+
+    /// Finds the first case in the source order that matches the given State and Read and
+    /// computes its outcome.
+    pub fn match_next_case(&self, state: &Expr<'nsa>, read: &Expr<'nsa>) -> Fallible<'nsa, Option<Outcome<'nsa>>> {
+        let mut candidates = vec![];
+        for state_key in Key::of_value(state) {
+            for read_key in Key::of_value(read) {
+                if let Some(indices) = self.index.get(&(state_key.clone(), read_key)) {
+                    candidates.extend_from_slice(indices);
+                }
+            }
+        }
+        candidates.sort_unstable();
+        for i in candidates {
+            if let Some(outcome) = self.cases[i].try_match(&self.sets, state, read)? {
+                return Ok(Some(outcome))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the program explicitly declares its halt states with `halt` statements.
+    pub fn declares_halts(&self) -> bool {
+        !self.halts.is_empty()
+    }
+
+    /// Whether the State matches any of the `halt` declarations.
+    pub fn is_halt(&self, state: &Expr<'nsa>) -> bool {
+        for (pattern, scope, destructured) in &self.halts {
+            let mut bindings = HashMap::new();
+            if pattern.pattern_match(state, scope, &mut bindings) && destructure_bindings(&mut bindings, scope, destructured) && bindings_in_scope(&bindings, scope, &self.sets) {
+                return true
+            }
+        }
+        false
+    }
+}
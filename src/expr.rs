@@ -2,15 +2,16 @@ use super::lexer::*;
 use std::fmt;
 use std::collections::HashMap;
 use super::{Result, Scope};
+use super::diagnostics::{Code, Diagnostic, Fallible};
 use std::hash::{Hash, Hasher};
-use std::num::IntErrorKind;
+use num_bigint::{BigInt, Sign};
 
 #[derive(Debug, Clone)]
 pub enum Atom<'nsa> {
     Symbol(Symbol<'nsa>),
     Integer {
         loc: Loc<'nsa>,
-        value: i64,
+        value: BigInt,
     },
     Real {
         loc: Loc<'nsa>,
@@ -41,44 +42,43 @@ impl<'nsa> Atom<'nsa> {
         }
     }
 
-    pub fn expect_real(&self) -> Result<f32> {
+    pub fn expect_real(&self) -> Fallible<'nsa, f32> {
         match self {
             &Self::Real{value, ..} => Ok(value),
-            _ => {
-                eprintln!("{loc}: ERROR: expected real value but got {human} `{value}`", loc = self.loc(), human = self.human(), value = self);
-                Err(())
-            }
+            _ => Err(Diagnostic::error(Code::TypeMismatch, *self.loc(), format!("expected real value but got {human} `{value}`", human = self.human(), value = self))),
         }
     }
 
-    pub fn expect_integer(&self) -> Result<i64> {
+    pub fn expect_integer(&self) -> Fallible<'nsa, &BigInt> {
         match self {
-            &Self::Integer{value, ..} => Ok(value),
-            _ => {
-                eprintln!("{loc}: ERROR: expected integer value but got {human} `{value}`", loc = self.loc(), human = self.human(), value = self);
-                Err(())
-            }
+            Self::Integer{value, ..} => Ok(value),
+            _ => Err(Diagnostic::error(Code::TypeMismatch, *self.loc(), format!("expected integer value but got {human} `{value}`", human = self.human(), value = self))),
         }
     }
 
-    pub fn expect_symbol(&self) -> Result<&Symbol<'nsa>> {
+    pub fn expect_symbol(&self) -> Fallible<'nsa, &Symbol<'nsa>> {
         match self {
             Self::Symbol(symbol) => Ok(symbol),
-            _ => {
-                eprintln!("{loc}: ERROR: expected symbol value but got {human} `{value}`", loc = self.loc(), human = self.human(), value = self);
-                Err(())
-            }
+            _ => Err(Diagnostic::error(Code::TypeMismatch, *self.loc(), format!("expected symbol value but got {human} `{value}`", human = self.human(), value = self))),
         }
     }
 
-    pub fn expect_string(&self) -> Result<&String> {
+    pub fn expect_string(&self) -> Fallible<'nsa, &String> {
         match self {
             Self::String{value, ..} => Ok(value),
-            _ => {
-                eprintln!("{loc}: ERROR: expected string value but got {human} `{value}`", loc = self.loc(), human = self.human(), value = self);
-                Err(())
+            _ => Err(Diagnostic::error(Code::TypeMismatch, *self.loc(), format!("expected string value but got {human} `{value}`", human = self.human(), value = self))),
+        }
+    }
+
+    /// Char is a String that consists of exactly one Unicode scalar value.
+    pub fn expect_char(&self) -> Fallible<'nsa, char> {
+        if let Self::String{value, ..} = self {
+            let mut chars = value.chars();
+            if let (Some(x), None) = (chars.next(), chars.next()) {
+                return Ok(x)
             }
         }
+        Err(Diagnostic::error(Code::TypeMismatch, *self.loc(), format!("expected char value but got {human} `{value}`", human = self.human(), value = self)))
     }
 
     pub fn from_symbol(symbol: Symbol<'nsa>) -> Result<Self> {
@@ -91,20 +91,11 @@ impl<'nsa> Atom<'nsa> {
             });
         }
 
-        match symbol.name.parse::<i64>() {
-            Ok(value) => return Ok(Atom::Integer{loc: symbol.loc, value}),
-            Err(err) => {
-                match err.kind() {
-                    IntErrorKind::PosOverflow => {
-                        eprintln!("{loc}: ERROR: could not parse Integer because positive overflow", loc = symbol.loc);
-                        return Err(())
-                    }
-                    IntErrorKind::NegOverflow => {
-                        eprintln!("{loc}: ERROR: could not parse Integer because negative overflow", loc = symbol.loc);
-                        return Err(())
-                    }
-                    _ => {}
-                }
+        // BigInt is more lenient than the syntax of the Integers (it accepts `1_000` for instance)
+        let digits = symbol.name.strip_prefix(['+', '-']).unwrap_or(symbol.name);
+        if !digits.is_empty() && digits.bytes().all(|x| x.is_ascii_digit()) {
+            if let Ok(value) = symbol.name.parse::<BigInt>() {
+                return Ok(Atom::Integer{loc: symbol.loc, value});
             }
         }
         if let Ok(value) = symbol.name.parse::<f32>() {
@@ -199,9 +190,9 @@ impl<'nsa> PartialEq for Expr<'nsa> {
                     Self::Eval{..} | Self::Tuple{..} => false,
                 }
             }
-            Self::Eval{lhs, rhs, ..} => {
+            Self::Eval{lhs, op, rhs, ..} => {
                 match other {
-                    Self::Eval{lhs: other_lhs, rhs: other_rhs, ..} => lhs == other_lhs && rhs == other_rhs,
+                    Self::Eval{lhs: other_lhs, op: other_op, rhs: other_rhs, ..} => lhs == other_lhs && op == other_op && rhs == other_rhs,
                     Self::Atom(_) | Self::Tuple{..} => false,
                 }
             }
@@ -222,8 +213,9 @@ impl<'nsa> Hash for Expr<'nsa> {
         match self {
             Self::Atom(atom) => atom.hash(h),
             Self::Tuple{elements, ..} => elements.hash(h),
-            Self::Eval{lhs, rhs, ..} => {
+            Self::Eval{lhs, op, rhs, ..} => {
                 lhs.hash(h);
+                op.hash(h);
                 rhs.hash(h);
             }
         }
@@ -245,7 +237,7 @@ impl<'nsa> fmt::Display for Expr<'nsa> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Atom(atom) => write!(f, "{atom}"),
-            Self::Eval{lhs, rhs, ..} => write!(f, "[{lhs} + {rhs}]"),
+            Self::Eval{lhs, op, rhs, ..} => write!(f, "[{lhs} {op} {rhs}]"),
             Self::Tuple{elements, ..} => {
                 write!(f, "(")?;
                 for (i, element) in elements.iter().enumerate() {
@@ -261,14 +253,74 @@ impl<'nsa> fmt::Display for Expr<'nsa> {
     }
 }
 
-fn expect_bool(symbol: &Symbol) -> Result<bool> {
+fn expect_bool<'nsa>(symbol: &Symbol<'nsa>) -> Fallible<'nsa, bool> {
     match symbol.name {
         "true" => Ok(true),
         "false" => Ok(false),
-        _ => {
-            eprintln!("{loc}: ERROR: expected boolean but got symbol {symbol}", loc = symbol.loc);
-            Err(())
+        _ => Err(Diagnostic::error(Code::TypeMismatch, symbol.loc, format!("expected boolean but got symbol {symbol}"))),
+    }
+}
+
+/// Evaluates the operations that convert between Chars, Bytes, Integers and Strings:
+/// - `[x as <Set>]` where `<Set>` is one of `Integer`, `Byte`, `Char` or `String`,
+/// - `[s at i]` the Char of the String at the index,
+/// - `[s length <Char | Byte>]` the amount of Chars or Bytes (in UTF-8) of the String.
+fn force_conversion<'nsa>(loc: Loc<'nsa>, lhs: Atom<'nsa>, op: Symbol<'nsa>, rhs: Expr<'nsa>) -> Fallible<'nsa, Expr<'nsa>> {
+    let rhs = rhs.expect_atom()?;
+    match op.name {
+        "as" => {
+            let set = rhs.expect_symbol()?;
+            let value = match (set.name, &lhs) {
+                ("Integer", Atom::Integer{value, ..}) => Atom::Integer{loc, value: value.clone()},
+                ("Integer", Atom::String{..}) => Atom::Integer{loc, value: BigInt::from(u32::from(lhs.expect_char()?))},
+                ("Byte", Atom::Integer{value, ..}) => {
+                    if u8::try_from(value).is_err() {
+                        return Err(Diagnostic::error(Code::Evaluation, loc, format!("integer {value} does not fit into a byte")))
+                    }
+                    Atom::Integer{loc, value: value.clone()}
+                }
+                ("Byte", Atom::String{..}) => {
+                    let x = lhs.expect_char()?;
+                    let Ok(byte) = u8::try_from(x) else {
+                        return Err(Diagnostic::error(Code::Evaluation, loc, format!("code of char '{x}' does not fit into a byte")))
+                    };
+                    Atom::Integer{loc, value: BigInt::from(byte)}
+                }
+                ("Char", Atom::Integer{value, ..}) => {
+                    let Some(x) = u32::try_from(value).ok().and_then(char::from_u32) else {
+                        return Err(Diagnostic::error(Code::Evaluation, loc, format!("integer {value} is not a code of any char")))
+                    };
+                    Atom::String{loc, value: x.to_string()}
+                }
+                ("Char", Atom::String{..}) => Atom::String{loc, value: lhs.expect_char()?.to_string()},
+                ("String", Atom::Integer{value, ..}) => Atom::String{loc, value: value.to_string()},
+                ("String", Atom::String{value, ..}) => Atom::String{loc, value: value.clone()},
+                _ => {
+                    return Err(Diagnostic::error(Code::Evaluation, loc, format!("can't convert {human} `{lhs}` to {set}", human = lhs.human())))
+                }
+            };
+            Ok(Expr::Atom(value))
+        }
+        "at" => {
+            let string = lhs.expect_string()?;
+            let index = rhs.expect_integer()?;
+            let Some(x) = usize::try_from(index).ok().and_then(|index| string.chars().nth(index)) else {
+                return Err(Diagnostic::error(Code::Evaluation, loc, format!("index {index} is out of bounds of string '{string}' of length {length}", length = string.chars().count())))
+            };
+            Ok(Expr::Atom(Atom::String{loc, value: x.to_string()}))
         }
+        "length" => {
+            let string = lhs.expect_string()?;
+            let length = match rhs.expect_symbol()?.name {
+                "Char" => string.chars().count(),
+                "Byte" => string.len(),
+                _ => {
+                    return Err(Diagnostic::error(Code::Evaluation, *rhs.loc(), format!("length may only be measured in Char or Byte but got `{rhs}`")))
+                }
+            };
+            Ok(Expr::Atom(Atom::Integer{loc, value: BigInt::from(length)}))
+        }
+        _ => unreachable!("not a conversion operation {op}"),
     }
 }
 
@@ -281,21 +333,15 @@ fn bool_to_str(cond: bool) -> &'static str {
 }
 
 impl<'nsa> Expr<'nsa> {
-    pub fn expect_atom(&self) -> Result<&Atom<'nsa>> {
+    pub fn expect_atom(&self) -> Fallible<'nsa, &Atom<'nsa>> {
         match self {
             Self::Atom(atom) => Ok(atom),
-            Self::Tuple{loc, ..} => {
-                eprintln!("{loc}: ERROR: expected atom but got tuple");
-                Err(())
-            }
-            Self::Eval{loc, ..} => {
-                eprintln!("{loc}: ERROR: expected atom but got eval");
-                Err(())
-            }
+            Self::Tuple{loc, ..} => Err(Diagnostic::error(Code::TypeMismatch, *loc, "expected atom but got tuple")),
+            Self::Eval{loc, ..} => Err(Diagnostic::error(Code::TypeMismatch, *loc, "expected atom but got eval")),
         }
     }
 
-    pub fn force_evals(self) -> Result<Expr<'nsa>> {
+    pub fn force_evals(self) -> Fallible<'nsa, Expr<'nsa>> {
         match self {
             Self::Atom(_) => Ok(self),
             Self::Tuple{loc, elements} => {
@@ -307,55 +353,35 @@ impl<'nsa> Expr<'nsa> {
             }
             Self::Eval{loc, lhs, op, rhs} => {
                 let lhs = lhs.force_evals()?.expect_atom()?.clone();
+                let op = op.force_evals()?;
+                if let Expr::Atom(Atom::Symbol(op @ Symbol{name: "as" | "at" | "length", ..})) = op {
+                    return force_conversion(loc, lhs, op, rhs.force_evals()?)
+                }
                 match lhs {
                     Atom::Integer{value: lhs, ..} => {
-                        let rhs = rhs.force_evals()?.expect_atom()?.expect_integer()?;
+                        let rhs = rhs.force_evals()?.expect_atom()?.expect_integer()?.clone();
                         let op  = *op.force_evals()?.expect_atom()?.expect_symbol()?;
                         match op.name {
                             "+" => Ok(Expr::Atom(Atom::Integer {
                                 loc,
-                                value: if let Some(value) = lhs.checked_add(rhs) {
-                                    value
-                                } else {
-                                    eprintln!("{loc}: ERROR: integer overflow while trying evaluate [{lhs} {op} {rhs}]");
-                                    return Err(());
-                                },
+                                value: lhs + rhs,
                             })),
                             "-" => Ok(Expr::Atom(Atom::Integer {
                                 loc,
-                                value: if let Some(value) = lhs.checked_sub(rhs) {
-                                    value
-                                } else {
-                                    eprintln!("{loc}: ERROR: integer overflow while trying evaluate [{lhs} {op} {rhs}]");
-                                    return Err(());
-                                },
+                                value: lhs - rhs,
                             })),
                             "*" => Ok(Expr::Atom(Atom::Integer {
                                 loc,
-                                value: if let Some(value) = lhs.checked_mul(rhs) {
-                                    value
-                                } else {
-                                    eprintln!("{loc}: ERROR: integer overflow while trying evaluate [{lhs} {op} {rhs}]");
-                                    return Err(());
-                                },
+                                value: lhs * rhs,
                             })),
+                            "/" | "%" if rhs.sign() == Sign::NoSign => Err(Diagnostic::error(Code::Evaluation, loc, format!("division by zero while trying evaluate [{lhs} {op} {rhs}]"))),
                             "/" => Ok(Expr::Atom(Atom::Integer {
                                 loc,
-                                value: if let Some(value) = lhs.checked_div(rhs) {
-                                    value
-                                } else {
-                                    eprintln!("{loc}: ERROR: integer overflow while trying evaluate [{lhs} {op} {rhs}]");
-                                    return Err(());
-                                },
+                                value: lhs / rhs,
                             })),
                             "%" => Ok(Expr::Atom(Atom::Integer {
                                 loc,
-                                value: if let Some(value) = lhs.checked_rem(rhs) {
-                                    value
-                                } else {
-                                    eprintln!("{loc}: ERROR: integer overflow while trying evaluate [{lhs} {op} {rhs}]");
-                                    return Err(());
-                                },
+                                value: lhs % rhs,
                             })),
                             ">" => Ok(Expr::Atom(Atom::Symbol(Symbol {
                                 loc,
@@ -381,10 +407,7 @@ impl<'nsa> Expr<'nsa> {
                                 loc,
                                 name: bool_to_str(lhs != rhs),
                             }))),
-                            _ => {
-                                eprintln!("{loc}: ERROR: Unexpected Integer operation {op}", loc = op.loc);
-                                Err(())
-                            }
+                            _ => Err(Diagnostic::error(Code::Evaluation, op.loc, format!("Unexpected Integer operation {op}"))),
                         }
                     }
                     Atom::Symbol(symbol) => {
@@ -408,10 +431,7 @@ impl<'nsa> Expr<'nsa> {
                                 loc,
                                 name: bool_to_str(lhs != rhs),
                             }))),
-                            _ => {
-                                eprintln!("{loc}: ERROR: Unexpected Boolean operation", loc = op.loc);
-                                Err(())
-                            }
+                            _ => Err(Diagnostic::error(Code::Evaluation, op.loc, "Unexpected Boolean operation")),
                         }
                     }
                     Atom::Real{value: lhs, ..} => {
@@ -462,10 +482,7 @@ impl<'nsa> Expr<'nsa> {
                                 loc,
                                 name: bool_to_str(lhs != rhs),
                             }))),
-                            _ => {
-                                eprintln!("{loc}: ERROR: Unexpected Integer operation {op}", loc = op.loc);
-                                Err(())
-                            }
+                            _ => Err(Diagnostic::error(Code::Evaluation, op.loc, format!("Unexpected Integer operation {op}"))),
                         }
                     }
                     Atom::String{value: lhs, ..} => {
@@ -500,10 +517,7 @@ impl<'nsa> Expr<'nsa> {
                                 loc,
                                 name: bool_to_str(lhs != *rhs),
                             }))),
-                            _ => {
-                                eprintln!("{loc}: ERROR: Unexpected Integer operation {op}", loc = op.loc);
-                                Err(())
-                            }
+                            _ => Err(Diagnostic::error(Code::Evaluation, op.loc, format!("Unexpected Integer operation {op}"))),
                         }
                     }
                 }
@@ -521,8 +535,8 @@ impl<'nsa> Expr<'nsa> {
             Self::Atom(Atom::Integer{..}) |
             Self::Atom(Atom::Real{..}) |
             Self::Atom(Atom::String{..})=> None,
-            Self::Eval{lhs, rhs, ..} => {
-                lhs.uses_var(var).or_else(|| rhs.uses_var(var))
+            Self::Eval{lhs, op, rhs, ..} => {
+                lhs.uses_var(var).or_else(|| op.uses_var(var)).or_else(|| rhs.uses_var(var))
             }
             Self::Tuple{elements, ..} => {
                 elements.iter().find_map(|element| element.uses_var(var))
@@ -530,6 +544,15 @@ impl<'nsa> Expr<'nsa> {
         }
     }
 
+    /// The leftmost Eval Expression of the expression if there is any.
+    pub fn first_eval(&self) -> Option<&Self> {
+        match self {
+            Self::Atom(_) => None,
+            Self::Eval{..} => Some(self),
+            Self::Tuple{elements, ..} => elements.iter().find_map(|element| element.first_eval()),
+        }
+    }
+
     pub fn substitute_bindings(&self, bindings: &HashMap<Symbol<'nsa>, Expr<'nsa>>) -> Expr<'nsa> {
         match self {
             Self::Atom(Atom::Symbol(symbol))  => {
@@ -0,0 +1,387 @@
+//! Source formatter of the Tula programs.
+//!
+//! The formatter prints the top level constructs of the root file of a [`Program`] (see
+//! [`Program::items`]) before the Universal Quantifiers are expanded, taking the text of the
+//! atoms and the comments from the source. The comments are kept on the lines they were
+//! attached to. The canonical layout:
+//! - one construct per line separated by at most one blank line,
+//! - the blocks are indented by 4 spaces,
+//! - the Universal Quantifiers over the same set are merged into `for a b in <set>`,
+//!   a quantifier stays on the same line with its body only if it was like that in the source,
+//! - the columns of the consecutive `let`s and cases of the same shape are aligned,
+//! - the Expressions, the set expressions and the tapes are separated by single spaces.
+//!   A tape that spans several lines keeps the cells on the lines they were on.
+
+use std::collections::HashMap;
+use std::fmt;
+use unicode_width::UnicodeWidthStr;
+use super::{Program, Item, Statement, Case, TapeSource, Cells, Written};
+use super::lexer::{Lexer, Loc, Symbol};
+use super::expr::Expr;
+use super::set_expr::SetExpr;
+
+const INDENT: &str = "    ";
+
+/// The row of the first symbol of the statement.
+fn first_row(statement: &Statement) -> usize {
+    match statement {
+        Statement::Halt{keyword, ..} | Statement::For{keyword, ..} => keyword.loc.row,
+        Statement::Case(case) => case.keyword.loc.row,
+        Statement::Block{open, ..} => open.loc.row,
+    }
+}
+
+/// The header of a Universal Quantifier.
+struct Header {
+    patterns: String,
+    set: String,
+    row: usize,
+    /// The body follows the header on the same row.
+    joined: bool,
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "for {patterns} in {set}", patterns = self.patterns, set = self.set)
+    }
+}
+
+/// Whether the blank lines in front of the line are kept.
+#[derive(Clone, Copy, PartialEq)]
+enum Spacing {
+    /// The line starts a construct.
+    Item,
+    /// The line continues the construct of the previous line.
+    Continuation,
+}
+
+struct Line {
+    indent: usize,
+    /// The cells of the consecutive lines of the same `kind` are aligned into columns. Every
+    /// cell but the last one is padded.
+    cells: Vec<String>,
+    kind: Option<&'static str>,
+    /// The source row of the first symbol of the line. The comments are placed relative to it.
+    row: Option<usize>,
+    spacing: Spacing,
+    comment: Option<String>,
+}
+
+impl Line {
+    fn new(indent: usize, text: String, row: usize, spacing: Spacing) -> Self {
+        Self {indent, cells: vec![text], kind: None, row: Some(row), spacing, comment: None}
+    }
+
+    fn aligned(indent: usize, cells: Vec<String>, kind: &'static str, row: usize, spacing: Spacing) -> Self {
+        Self {indent, cells, kind: Some(kind), row: Some(row), spacing, comment: None}
+    }
+
+    fn opens(&self) -> bool {
+        self.cells.last().is_some_and(|cell| cell.ends_with('{'))
+    }
+
+    fn closes(&self) -> bool {
+        self.cells.first().is_some_and(|cell| cell.starts_with('}'))
+    }
+}
+
+struct Formatter<'a, 'nsa> {
+    program: &'a Program<'nsa>,
+    /// The source text of every symbol of the file by its position.
+    texts: HashMap<(usize, usize), &'nsa str>,
+    /// The rows of all the symbols and the comments in ascending order.
+    rows: Vec<usize>,
+    /// The comments together with whether they follow some code on the same row.
+    comments: Vec<(Symbol<'nsa>, bool)>,
+    lines: Vec<Line>,
+}
+
+impl<'nsa> Formatter<'_, 'nsa> {
+    fn text(&self, loc: &Loc<'nsa>) -> &'nsa str {
+        self.texts.get(&(loc.row, loc.col)).copied().expect("every atom comes from a symbol of the file")
+    }
+
+    fn expr(&self, expr: &Expr<'nsa>) -> String {
+        match expr {
+            Expr::Atom(atom) => self.text(atom.loc()).to_string(),
+            Expr::Eval{lhs, op, rhs, ..} => format!("[{lhs} {op} {rhs}]", lhs = self.expr(lhs), op = self.expr(op), rhs = self.expr(rhs)),
+            Expr::Tuple{elements, ..} => format!("({elements})", elements = self.exprs(elements)),
+        }
+    }
+
+    fn exprs(&self, exprs: &[Expr<'nsa>]) -> String {
+        exprs.iter().map(|expr| self.expr(expr)).collect::<Vec<_>>().join(" ")
+    }
+
+    fn set(&self, set: &SetExpr<'nsa>) -> String {
+        match set {
+            SetExpr::Named(symbol) | SetExpr::Integer(symbol) | SetExpr::Real(symbol) | SetExpr::String(symbol) |
+            SetExpr::Boolean(symbol) | SetExpr::Char(symbol) | SetExpr::Byte(symbol) => symbol.name.to_string(),
+            SetExpr::Range{loc, ..} => self.text(loc).to_string(),
+            SetExpr::Enclosed{inner, ..} => format!("({inner})", inner = self.set(inner)),
+            SetExpr::Anonymous{elements, ..} if elements.is_empty() => "{}".to_string(),
+            SetExpr::Anonymous{elements, ..} => format!("{{ {elements} }}", elements = self.exprs(elements)),
+            SetExpr::Union{lhs, rhs} => format!("{lhs} + {rhs}", lhs = self.set(lhs), rhs = self.set(rhs)),
+            SetExpr::Diff{lhs, rhs} => format!("{lhs} - {rhs}", lhs = self.set(lhs), rhs = self.set(rhs)),
+            SetExpr::Product{elements} => elements.iter().map(|element| self.set(element)).collect::<Vec<_>>().join(" * "),
+        }
+    }
+
+    /// Appends the tape to the `line`. A tape that spans several rows of the source is broken
+    /// into several lines, then the `line` is left with the closing curly brace.
+    fn tape(&mut self, tape: &TapeSource<'nsa>, indent: usize, line: &mut (String, usize, Spacing)) {
+        match tape {
+            TapeSource::File(path) => line.0.push_str(&format!(" @{path}")),
+            TapeSource::Inline(seqs) => for Cells{open, cells, close} in seqs {
+                if cells.is_empty() {
+                    line.0.push_str(" {}");
+                } else if cells.iter().all(|cell| cell.loc().row == open.loc.row) && close.loc.row == open.loc.row {
+                    line.0.push_str(&format!(" {{ {cells} }}", cells = self.exprs(cells)));
+                } else {
+                    line.0.push_str(" {");
+                    let (text, row, spacing) = std::mem::replace(line, ("}".to_string(), close.loc.row, Spacing::Continuation));
+                    self.lines.push(Line::new(indent, text, row, spacing));
+                    for group in cells.chunk_by(|a, b| a.loc().row == b.loc().row) {
+                        self.lines.push(Line::new(indent + 1, self.exprs(group), group[0].loc().row, Spacing::Continuation));
+                    }
+                }
+            }
+        }
+    }
+
+    fn item(&mut self, item: &Item<'nsa>) {
+        let program = self.program;
+        match item {
+            Item::Include{keyword, path} => {
+                self.lines.push(Line::new(0, format!("{keyword} {path}"), keyword.loc.row, Spacing::Item));
+            }
+            Item::Let{keyword, name} => {
+                let set = &program.sets[name];
+                let cells = vec![keyword.to_string(), name.to_string(), self.set(set)];
+                self.lines.push(Line::aligned(0, cells, "let", keyword.loc.row, Spacing::Item));
+            }
+            Item::Statement(i) => self.statement(&program.statements[*i], 0, Spacing::Item),
+            Item::Run(i) => {
+                let run = &program.runs[*i];
+                let mut line = (run.keyword.to_string(), run.keyword.loc.row, Spacing::Item);
+                self.config(&run.written, &mut line);
+                self.lines.push(Line::new(0, line.0, line.1, line.2));
+            }
+            Item::Test(i) => {
+                let test = &program.tests[*i];
+                let mut line = (test.keyword.to_string(), test.keyword.loc.row, Spacing::Item);
+                self.config(&test.written, &mut line);
+                line.0.push_str(&format!(" {expect}", expect = test.expect));
+                self.config(&test.expected_written, &mut line);
+                if let Some(head) = test.expected_head {
+                    line.0.push_str(&format!(" head {head}"));
+                }
+                if let Some(max_steps) = test.max_steps {
+                    line.0.push_str(&format!(" max-steps {max_steps}"));
+                }
+                self.lines.push(Line::new(0, line.0, line.1, line.2));
+            }
+        }
+    }
+
+    /// Appends the state and the tape to the `line`.
+    fn config(&mut self, written: &Written<'nsa>, line: &mut (String, usize, Spacing)) {
+        line.0.push_str(&format!(" {state}", state = self.expr(&written.state)));
+        self.tape(&written.tape, 0, line);
+    }
+
+    fn statement(&mut self, statement: &Statement<'nsa>, indent: usize, spacing: Spacing) {
+        match statement {
+            Statement::Case(case) => {
+                let cells = self.case(case);
+                self.lines.push(Line::aligned(indent, cells, "case", case.keyword.loc.row, spacing));
+            }
+            Statement::Halt{keyword, state} => {
+                let cells = vec![keyword.to_string(), self.expr(state)];
+                self.lines.push(Line::aligned(indent, cells, "halt", keyword.loc.row, spacing));
+            }
+            Statement::For{..} => {
+                // Nested quantifiers over the same set are merged into one. A header is kept on
+                // the same line with its body if it was like that in the source.
+                let mut headers: Vec<Header> = vec![];
+                let mut body = statement;
+                while let Statement::For{keyword, pattern, set, body: inner} = body {
+                    let set = self.set(set);
+                    let joined = first_row(inner) == keyword.loc.row;
+                    match headers.last_mut() {
+                        Some(last) if last.set == set => {
+                            last.patterns.push(' ');
+                            last.patterns.push_str(&self.expr(pattern));
+                            last.joined = joined;
+                        }
+                        _ => headers.push(Header{patterns: self.expr(pattern), set, row: keyword.loc.row, joined}),
+                    }
+                    body = inner;
+                }
+                let block = matches!(body, Statement::Block{..});
+                let mut pending: Vec<Header> = vec![];
+                let mut spacing = spacing;
+                let count = headers.len();
+                for (i, header) in headers.into_iter().enumerate() {
+                    let last = i + 1 == count;
+                    let joined = header.joined && !block;
+                    pending.push(header);
+                    if !joined || (last && block) {
+                        let row = pending[0].row;
+                        let mut text = pending.drain(..).map(|header| header.to_string()).collect::<Vec<_>>().join(" ");
+                        if last && block {
+                            text.push_str(" {");
+                        }
+                        self.lines.push(Line::new(indent, text, row, spacing));
+                        spacing = Spacing::Continuation;
+                    }
+                }
+                match (body, pending.as_slice()) {
+                    (Statement::Block{statements, close, ..}, _) => self.block_rest(statements, close, indent),
+                    (_, []) => self.statement(body, indent, Spacing::Continuation),
+                    (Statement::Case(case), [header]) => {
+                        let mut cells = vec![format!("for {patterns}", patterns = header.patterns), format!("in {set}", set = header.set)];
+                        cells.extend(self.case(case));
+                        self.lines.push(Line::aligned(indent, cells, "for case", header.row, spacing));
+                    }
+                    (Statement::Halt{keyword, state}, [header]) => {
+                        let cells = vec![format!("for {patterns}", patterns = header.patterns), format!("in {set}", set = header.set), keyword.to_string(), self.expr(state)];
+                        self.lines.push(Line::aligned(indent, cells, "for halt", header.row, spacing));
+                    }
+                    (_, headers) => {
+                        // Several headers on the same line with a case or a halt are not aligned
+                        let row = headers[0].row;
+                        let mut cells: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+                        let start = self.lines.len();
+                        self.statement(body, indent, Spacing::Continuation);
+                        let leaf = self.lines.drain(start..).next().expect("cases and halts are single lines");
+                        cells.push(leaf.cells.join(" "));
+                        self.lines.push(Line::new(indent, cells.join(" "), row, spacing));
+                    }
+                }
+            }
+            Statement::Block{open, statements, close} => {
+                self.lines.push(Line::new(indent, "{".to_string(), open.loc.row, spacing));
+                self.block_rest(statements, close, indent);
+            }
+        }
+    }
+
+    fn case(&self, case: &Case<'nsa>) -> Vec<String> {
+        let Case{keyword, state, read, write, step, next} = case;
+        vec![keyword.to_string(), self.expr(state), self.expr(read), self.expr(write), self.expr(step), self.expr(next)]
+    }
+
+    /// Emits the statements of a block which opening curly brace has been emitted already.
+    fn block_rest(&mut self, statements: &[Statement<'nsa>], close: &Symbol<'nsa>, indent: usize) {
+        if statements.is_empty() {
+            let line = self.lines.last_mut().expect("the opening curly brace was emitted");
+            line.cells.last_mut().expect("lines are never empty").push('}');
+            return
+        }
+        for statement in statements {
+            self.statement(statement, indent + 1, Spacing::Item);
+        }
+        self.lines.push(Line::new(indent, "}".to_string(), close.loc.row, Spacing::Continuation));
+    }
+
+    /// Whether there is a blank line in the source right in front of the row.
+    fn blank_before(&self, row: usize) -> bool {
+        let i = self.rows.partition_point(|other| *other < row);
+        i > 0 && self.rows[i - 1] + 1 < row
+    }
+}
+
+/// Weaves the comments and the blank lines of the source into the formatted lines.
+fn weave(formatter: &Formatter, lines: Vec<Line>) -> Vec<Line> {
+    let mut result: Vec<Line> = vec![];
+    let mut comments = formatter.comments.iter().peekable();
+    let mut last_row = 0;
+    let push = |result: &mut Vec<Line>, line: Line, last_row: &mut usize| {
+        if let Some(row) = line.row {
+            let starts = line.spacing == Spacing::Item && row > *last_row;
+            if starts && formatter.blank_before(row) && result.last().is_some_and(|last| !last.cells.is_empty() && !last.opens()) && !line.closes() {
+                result.push(Line{indent: 0, cells: vec![], kind: None, row: None, spacing: Spacing::Continuation, comment: None});
+            }
+            *last_row = (*last_row).max(row);
+        }
+        result.push(line);
+    };
+    for line in lines {
+        let row = line.row.unwrap_or(usize::MAX);
+        while let Some((comment, trailing)) = comments.next_if(|(comment, _)| comment.loc.row < row) {
+            match result.last_mut() {
+                Some(last) if *trailing && last.comment.is_none() && !last.cells.is_empty() => {
+                    last.comment = Some(comment.name.to_string());
+                }
+                _ => {
+                    let indent = if line.closes() { line.indent + 1 } else { line.indent };
+                    push(&mut result, Line::new(indent, comment.name.to_string(), comment.loc.row, Spacing::Item), &mut last_row);
+                }
+            }
+        }
+        push(&mut result, line, &mut last_row);
+    }
+    for (comment, trailing) in comments {
+        match result.last_mut() {
+            Some(last) if *trailing && last.comment.is_none() && !last.cells.is_empty() => {
+                last.comment = Some(comment.name.to_string());
+            }
+            _ => push(&mut result, Line::new(0, comment.name.to_string(), comment.loc.row, Spacing::Item), &mut last_row),
+        }
+    }
+    result
+}
+
+/// Pads the cells of the consecutive lines of the same kind and indentation into columns.
+fn align(lines: &mut [Line]) {
+    for group in lines.chunk_by_mut(|a, b| a.kind.is_some() && a.kind == b.kind && a.indent == b.indent) {
+        let columns = group.iter().map(|line| line.cells.len()).max().unwrap_or(0);
+        for column in 0..columns.saturating_sub(1) {
+            let width = group.iter()
+                .filter(|line| column + 1 < line.cells.len())
+                .map(|line| UnicodeWidthStr::width(line.cells[column].as_str()))
+                .max()
+                .unwrap_or(0);
+            for line in group.iter_mut().filter(|line| column + 1 < line.cells.len()) {
+                let cell = &mut line.cells[column];
+                let pad = width - UnicodeWidthStr::width(cell.as_str());
+                cell.extend(std::iter::repeat_n(' ', pad));
+            }
+        }
+    }
+}
+
+/// Formats the source of the root file of the `program` that was parsed from it.
+pub fn format_source<'nsa>(source: &'nsa str, file_path: &'nsa str, program: &Program<'nsa>) -> String {
+    let mut symbols = Lexer::new(source, file_path);
+    let texts: HashMap<_, _> = symbols.by_ref().map(|symbol| ((symbol.loc.row, symbol.loc.col), symbol.name)).collect();
+    let comments: Vec<_> = symbols.comments.iter().map(|comment| {
+        let trailing = texts.keys().any(|(row, col)| *row == comment.loc.row && *col < comment.loc.col);
+        (*comment, trailing)
+    }).collect();
+    let mut rows: Vec<usize> = texts.keys().map(|(row, _)| *row).chain(comments.iter().map(|(comment, _)| comment.loc.row)).collect();
+    rows.sort();
+    rows.dedup();
+
+    let mut formatter = Formatter{program, texts, rows, comments, lines: vec![]};
+    for item in &program.items {
+        formatter.item(item);
+    }
+    let lines = std::mem::take(&mut formatter.lines);
+    let mut lines = weave(&formatter, lines);
+    align(&mut lines);
+
+    let mut result = String::new();
+    for line in lines {
+        let mut text = INDENT.repeat(line.indent);
+        text.push_str(&line.cells.join(" "));
+        if let Some(comment) = line.comment {
+            text.push(' ');
+            text.push_str(&comment);
+        }
+        result.push_str(text.trim_end());
+        result.push('\n');
+    }
+    result
+}
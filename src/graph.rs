@@ -0,0 +1,290 @@
+//! State-transition graph of the Tula programs in the Graphviz DOT format.
+//!
+//! The cases are expanded like in [`Statement::expand`], except that the Universal Quantifiers
+//! over the sets that are too big to expand (like `Integer`) are kept. The States that depend on
+//! such variables become symbolic nodes that stand for all the States matching the pattern.
+//! The Next of a case is connected to every node it may be equal to.
+
+use std::collections::HashMap;
+use std::fmt;
+use super::{Program, Statement, Case, Scope, Quantifier, pattern_vars};
+use super::lexer::Symbol;
+use super::expr::Expr;
+use super::set_expr::Sets;
+use super::diagnostics::{Code, Fallible};
+use super::overlap::may_be_equal;
+
+/// The variables that were not expanded in the order of their quantifiers.
+type Free<'nsa> = Vec<(Symbol<'nsa>, Quantifier<'nsa>)>;
+
+/// A case or a `halt` declaration with the expanded variables substituted.
+enum Leaf<'nsa> {
+    Case {
+        case: Box<Case<'nsa>>,
+        free: Free<'nsa>,
+    },
+    Halt {
+        state: Expr<'nsa>,
+        free: Free<'nsa>,
+    },
+}
+
+fn expand_leaves<'nsa>(statement: &Statement<'nsa>, sets: &Sets<'nsa>, bindings: &mut HashMap<Symbol<'nsa>, Expr<'nsa>>, free: &mut Free<'nsa>, result: &mut Vec<Leaf<'nsa>>) -> Fallible<'nsa, ()> {
+    match statement {
+        Statement::Case(case) => {
+            let mut case = case.substitute_bindings(bindings);
+            case.write = force_closed(case.write, free)?;
+            case.step = force_closed(case.step, free)?;
+            case.next = force_closed(case.next, free)?;
+            result.push(Leaf::Case{case: Box::new(case), free: free.clone()});
+        }
+        Statement::Halt{state, ..} => {
+            result.push(Leaf::Halt{state: state.substitute_bindings(bindings), free: free.clone()});
+        }
+        Statement::For{pattern, set, body, ..} => {
+            let mut vars = vec![];
+            pattern_vars(pattern, &mut vars);
+            let quantifier = Quantifier{pattern: pattern.clone(), set: set.clone()};
+            match set.expand(sets) {
+                Ok(elements) => {
+                    let scope: Scope<'nsa> = vars.iter().map(|var| (*var, quantifier.clone())).collect();
+                    for element in &elements {
+                        let mut destructured = HashMap::new();
+                        if !pattern.pattern_match(element, &scope, &mut destructured) {
+                            continue
+                        }
+                        bindings.extend(destructured);
+                        expand_leaves(body, sets, bindings, free, result)?;
+                        for var in &vars {
+                            bindings.remove(var);
+                        }
+                    }
+                }
+                Err(diagnostic) if diagnostic.code == Code::InfiniteSet => {
+                    let len = free.len();
+                    free.extend(vars.iter().map(|var| (*var, quantifier.clone())));
+                    expand_leaves(body, sets, bindings, free, result)?;
+                    free.truncate(len);
+                }
+                Err(diagnostic) => return Err(diagnostic),
+            }
+        }
+        Statement::Block{statements, ..} => {
+            for statement in statements {
+                expand_leaves(statement, sets, bindings, free, result)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The free variables the expression depends on.
+fn free_in<'nsa>(expr: &Expr<'nsa>, free: &Free<'nsa>) -> Free<'nsa> {
+    free.iter().filter(|(var, _)| expr.uses_var(var).is_some()).cloned().collect()
+}
+
+/// Forces the parts of the expression that don't depend on the free variables. The Evals that
+/// do stay as they are.
+fn force_closed<'nsa>(expr: Expr<'nsa>, free: &Free<'nsa>) -> Fallible<'nsa, Expr<'nsa>> {
+    if free_in(&expr, free).is_empty() {
+        return expr.force_evals()
+    }
+    match expr {
+        Expr::Atom(_) => Ok(expr),
+        Expr::Tuple{loc, elements} => {
+            let mut forced = vec![];
+            for element in elements {
+                forced.push(force_closed(element, free)?);
+            }
+            Ok(Expr::Tuple{loc, elements: forced})
+        }
+        Expr::Eval{loc, lhs, op, rhs} => Ok(Expr::Eval {
+            loc,
+            lhs: Box::new(force_closed(*lhs, free)?),
+            op: Box::new(force_closed(*op, free)?),
+            rhs: Box::new(force_closed(*rhs, free)?),
+        }),
+    }
+}
+
+/// A concrete State of the Machine or a pattern of the States if it has free variables.
+struct Node<'nsa> {
+    state: Expr<'nsa>,
+    free: Free<'nsa>,
+    halt: bool,
+}
+
+impl<'nsa> Node<'nsa> {
+    fn scope(&self) -> Scope<'nsa> {
+        self.free.iter().cloned().collect()
+    }
+
+    /// The State followed by the quantifiers of the free variables. The consecutive variables
+    /// of the same set are merged into one quantifier like in the source.
+    fn label(&self) -> String {
+        let mut label = self.state.to_string();
+        let mut quantifiers: Vec<(String, String)> = vec![];
+        for (var, Quantifier{pattern, set}) in &self.free {
+            let pattern = match pattern {
+                Expr::Atom(_) => var.to_string(),
+                _ => pattern.to_string(),
+            };
+            let set = set.to_string();
+            match quantifiers.last_mut() {
+                Some((patterns, last_set)) if *last_set == set => {
+                    if !patterns.split(' ').any(|other| other == pattern) {
+                        patterns.push(' ');
+                        patterns.push_str(&pattern);
+                    }
+                }
+                _ => quantifiers.push((pattern, set)),
+            }
+        }
+        for (patterns, set) in quantifiers {
+            label.push_str(&format!("\nfor {patterns} in {set}"));
+        }
+        label
+    }
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: String,
+}
+
+/// The graph of the Machine. Displayed in the DOT format.
+pub struct Graph<'nsa> {
+    nodes: Vec<Node<'nsa>>,
+    /// The nodes by their labels.
+    index: HashMap<String, usize>,
+    edges: Vec<Edge>,
+    /// The `run`s and `trace`s of the program and the nodes of their entry States.
+    entries: Vec<(String, Vec<usize>)>,
+}
+
+impl<'nsa> Graph<'nsa> {
+    /// Builds the graph of the program. Expects the program to be sanity checked.
+    pub fn new(program: &Program<'nsa>) -> Fallible<'nsa, Self> {
+        let mut leaves = vec![];
+        for statement in &program.statements {
+            expand_leaves(statement, &program.sets, &mut HashMap::new(), &mut vec![], &mut leaves)?;
+        }
+        let mut graph = Graph{nodes: vec![], index: HashMap::new(), edges: vec![], entries: vec![]};
+        for leaf in &leaves {
+            match leaf {
+                Leaf::Case{case, free} => {
+                    graph.node(&case.state, free);
+                }
+                Leaf::Halt{state, free} => {
+                    let node = graph.node(state, free);
+                    graph.nodes[node].halt = true;
+                }
+            }
+        }
+        // The edges may add the States without cases, so the targets are looked up among the
+        // nodes of the cases and the `halt` declarations only
+        let declared = graph.nodes.len();
+        let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+        for leaf in &leaves {
+            if let Leaf::Case{case, free} = leaf {
+                let Case{state, read, write, step, next, ..} = &**case;
+                let from = graph.node(state, free);
+                let label = format!("{read}/{write},{step}");
+                for to in graph.targets(&program.sets, next, free, declared) {
+                    // The parallel edges are merged into one with a line per case
+                    match edges.get(&(from, to)) {
+                        Some(index) => {
+                            let edge = &mut graph.edges[*index];
+                            edge.label.push('\n');
+                            edge.label.push_str(&label);
+                        }
+                        None => {
+                            edges.insert((from, to), graph.edges.len());
+                            graph.edges.push(Edge{from, to, label: label.clone()});
+                        }
+                    }
+                }
+            }
+        }
+        for run in &program.runs {
+            let targets = graph.targets(&program.sets, &run.state, &Vec::new(), declared);
+            graph.entries.push((run.kind.to_string(), targets));
+        }
+        Ok(graph)
+    }
+
+    /// Finds or adds the node of the State.
+    fn node(&mut self, state: &Expr<'nsa>, free: &Free<'nsa>) -> usize {
+        let free = free_in(state, free);
+        let node = Node{state: state.clone(), free, halt: false};
+        let label = node.label();
+        if let Some(index) = self.index.get(&label) {
+            return *index
+        }
+        self.nodes.push(node);
+        self.index.insert(label, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    /// The nodes among the first `declared` ones the State may be equal to. The State gets its
+    /// own node if there are none.
+    fn targets(&mut self, sets: &Sets<'nsa>, state: &Expr<'nsa>, free: &Free<'nsa>, declared: usize) -> Vec<usize> {
+        let scope: Scope<'nsa> = free.iter().cloned().collect();
+        let targets: Vec<usize> = (0..declared)
+            .filter(|index| {
+                let node = &self.nodes[*index];
+                may_be_equal(sets, state, &scope, &node.state, &node.scope())
+            })
+            .collect();
+        if targets.is_empty() {
+            vec![self.node(state, free)]
+        } else {
+            targets
+        }
+    }
+}
+
+/// Escapes the string for a quoted DOT identifier with `\n` breaking the lines.
+struct Dot<'a>(&'a str);
+
+impl<'a> fmt::Display for Dot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for x in self.0.chars() {
+            match x {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                _ => write!(f, "{x}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl<'nsa> fmt::Display for Graph<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph tula {{")?;
+        for (index, node) in self.nodes.iter().enumerate() {
+            write!(f, "    s{index} [label={label}", label = Dot(&node.label()))?;
+            if !node.free.is_empty() {
+                write!(f, ", shape=box, style=dashed")?;
+            }
+            if node.halt {
+                write!(f, ", peripheries=2")?;
+            }
+            writeln!(f, "];")?;
+        }
+        for Edge{from, to, label} in &self.edges {
+            writeln!(f, "    s{from} -> s{to} [label={label}];", label = Dot(label))?;
+        }
+        for (index, (kind, targets)) in self.entries.iter().enumerate() {
+            writeln!(f, "    entry{index} [shape=point];")?;
+            for to in targets {
+                writeln!(f, "    entry{index} -> s{to} [label={kind}];", kind = Dot(kind))?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
@@ -0,0 +1,263 @@
+//! Just enough of JSON to emit machine-readable output and to talk to the editors without
+//! pulling in any dependencies.
+
+use std::fmt;
+
+/// Displays the wrapped string as a JSON string literal with all the necessary escaping.
+pub struct Str<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Str<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for x in self.0.chars() {
+            match x {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                x if (x as u32) < 0x20 => write!(f, "\\u{code:04x}", code = x as u32)?,
+                x => write!(f, "{x}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// Parsed JSON value. Objects keep the order of their fields, so the output is stable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object<'a, I>(fields: I) -> Self where I: IntoIterator<Item = (&'a str, Value)> {
+        Value::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// Parses the whole source as a single value.
+    pub fn parse(source: &str) -> Option<Self> {
+        let mut parser = Parser{source, pos: 0};
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos < source.len() {
+            return None
+        }
+        Some(value)
+    }
+
+    /// The field of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Value::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Some(*number as usize),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::String(string.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(string)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(number: usize) -> Self {
+        Value::Number(number as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Number(number) if number.is_finite() && number.fract() == 0.0 => write!(f, "{number:.0}"),
+            Value::Number(number) if number.is_finite() => write!(f, "{number}"),
+            Value::Number(_) => write!(f, "null"),
+            Value::String(string) => write!(f, "{string}", string = Str(string)),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{element}")?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{key}:{value}", key = Str(key))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn strip_prefix(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let x = self.rest().chars().next()?;
+        self.pos += x.len_utf8();
+        Some(x)
+    }
+
+    fn parse_value(&mut self) -> Option<Value> {
+        self.skip_whitespace();
+        if self.strip_prefix("null") {
+            return Some(Value::Null)
+        }
+        if self.strip_prefix("true") {
+            return Some(Value::Bool(true))
+        }
+        if self.strip_prefix("false") {
+            return Some(Value::Bool(false))
+        }
+        if self.rest().starts_with('"') {
+            return self.parse_string().map(Value::String)
+        }
+        if self.strip_prefix("[") {
+            let mut elements = vec![];
+            self.skip_whitespace();
+            if self.strip_prefix("]") {
+                return Some(Value::Array(elements))
+            }
+            loop {
+                elements.push(self.parse_value()?);
+                self.skip_whitespace();
+                if self.strip_prefix("]") {
+                    return Some(Value::Array(elements))
+                }
+                if !self.strip_prefix(",") {
+                    return None
+                }
+            }
+        }
+        if self.strip_prefix("{") {
+            let mut fields = vec![];
+            self.skip_whitespace();
+            if self.strip_prefix("}") {
+                return Some(Value::Object(fields))
+            }
+            loop {
+                self.skip_whitespace();
+                let key = self.parse_string()?;
+                self.skip_whitespace();
+                if !self.strip_prefix(":") {
+                    return None
+                }
+                fields.push((key, self.parse_value()?));
+                self.skip_whitespace();
+                if self.strip_prefix("}") {
+                    return Some(Value::Object(fields))
+                }
+                if !self.strip_prefix(",") {
+                    return None
+                }
+            }
+        }
+        let rest = self.rest();
+        let length = rest.find(|x: char| !matches!(x, '0'..='9' | '-' | '+' | '.' | 'e' | 'E')).unwrap_or(rest.len());
+        let number = rest[..length].parse::<f64>().ok()?;
+        self.pos += length;
+        Some(Value::Number(number))
+    }
+
+    fn parse_hex4(&mut self) -> Option<u32> {
+        let digits = self.rest().get(..4)?;
+        let code = u32::from_str_radix(digits, 16).ok()?;
+        self.pos += 4;
+        Some(code)
+    }
+
+    fn parse_string(&mut self) -> Option<String> {
+        if !self.strip_prefix("\"") {
+            return None
+        }
+        let mut string = String::new();
+        loop {
+            match self.next_char()? {
+                '"' => return Some(string),
+                '\\' => match self.next_char()? {
+                    '"' => string.push('"'),
+                    '\\' => string.push('\\'),
+                    '/' => string.push('/'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'n' => string.push('\n'),
+                    'r' => string.push('\r'),
+                    't' => string.push('\t'),
+                    'u' => {
+                        let mut code = self.parse_hex4()?;
+                        if (0xD800..0xDC00).contains(&code) && self.strip_prefix("\\u") {
+                            let low = self.parse_hex4()?;
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low.checked_sub(0xDC00)? & 0x3FF);
+                        }
+                        string.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return None,
+                },
+                x => string.push(x),
+            }
+        }
+    }
+}
@@ -2,10 +2,11 @@ use std::iter::Iterator;
 use std::fmt::{self, Write};
 use std::hash::{Hash, Hasher};
 use super::Result;
+use super::diagnostics::{Code, Diagnostic, Diagnostics};
 
 pub const SPECIAL: &[char] = &['(', ')', '{', '}', '[', ']'];
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Loc<'nsa> {
     pub file_path: &'nsa str,
     pub row: usize,
@@ -40,7 +41,7 @@ impl<'nsa> Hash for Symbol<'nsa> {
 
 impl<'nsa> PartialOrd for Symbol<'nsa> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.name.partial_cmp(other.name)
+        Some(self.cmp(other))
     }
 }
 
@@ -64,6 +65,11 @@ pub struct Lexer<'nsa> {
     bol: usize,
     row: usize,
     peek: Option<Symbol<'nsa>>,
+    /// The comments skipped so far including the leading `//`. Nothing but the tools that
+    /// reproduce the source (like the formatter) cares about them.
+    pub comments: Vec<Symbol<'nsa>>,
+    /// Everything reported while parsing the source of the Lexer.
+    pub diagnostics: Diagnostics<'nsa>,
 }
 
 impl<'nsa> Lexer<'nsa> {
@@ -75,6 +81,12 @@ impl<'nsa> Lexer<'nsa> {
             bol: 0,
             row: 0,
             peek: None,
+            comments: vec![],
+            diagnostics: {
+                let mut diagnostics = Diagnostics::default();
+                diagnostics.add_source(file_path, source);
+                diagnostics
+            },
         }
     }
 
@@ -144,8 +156,12 @@ impl<'nsa> Lexer<'nsa> {
     fn chop_symbol(&mut self) -> Option<Symbol<'nsa>> {
         'strip_whitespaces_and_comments: loop {
             let _ = self.strip_while(|x| x.is_whitespace());
+            let loc = self.loc();
+            let source = self.source;
             if self.strip_prefix("//") {
                 let _ = self.strip_while(|x| *x != '\n');
+                let name = source[..source.len() - self.source.len()].trim_end();
+                self.comments.push(Symbol{name, loc});
             } else {
                 break 'strip_whitespaces_and_comments
             }
@@ -201,7 +217,8 @@ impl<'nsa> Lexer<'nsa> {
         if let Some(symbol) = self.next_symbol() {
             Ok(symbol)
         } else {
-            eprintln!("{loc}: ERROR: expected symbol but reached the end of the input", loc = self.loc());
+            let loc = self.loc();
+            self.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, loc, "expected symbol but reached the end of the input"));
             Err(())
         }
     }
@@ -223,9 +240,40 @@ impl<'nsa> Lexer<'nsa> {
                 let _ = write!(&mut buffer, ", {name}");
             }
         }
-        eprintln!("{loc}: ERROR: expected {buffer} got {name}", loc = symbol.loc, name = symbol.name);
+        self.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, symbol.loc, format!("expected {buffer} got {name}", name = symbol.name)));
         Err(())
     }
+
+    /// Lexes another source (like an included file) with `f` reporting into the same diagnostics.
+    pub fn nested<T, F>(&mut self, source: &'nsa str, file_path: &'nsa str, f: F) -> T where F: FnOnce(&mut Lexer<'nsa>) -> T {
+        let mut lexer = Lexer::new(source, file_path);
+        lexer.diagnostics = std::mem::take(&mut self.diagnostics);
+        lexer.diagnostics.add_source(file_path, source);
+        let result = f(&mut lexer);
+        self.diagnostics = lexer.diagnostics;
+        result
+    }
+
+    /// Skips the rest of the construct that started at `start` and failed to parse up to the
+    /// next symbol from `keywords` that is not nested in curly braces, so parsing can carry on
+    /// and report the problems of the following constructs too.
+    pub fn recover(&mut self, start: Loc<'nsa>, keywords: &[&str]) {
+        if let Some(symbol) = self.peek_symbol() {
+            if symbol.loc.row == start.row && symbol.loc.col == start.col {
+                self.next_symbol();
+            }
+        }
+        let mut depth = 0usize;
+        while let Some(symbol) = self.peek_symbol() {
+            match symbol.name {
+                "{" => depth += 1,
+                "}" => depth = depth.saturating_sub(1),
+                name if depth == 0 && keywords.contains(&name) => break,
+                _ => {}
+            }
+            self.next_symbol();
+        }
+    }
 }
 
 impl<'nsa> Iterator for Lexer<'nsa> {
@@ -0,0 +1,1227 @@
+// Errors are reported into the `Diagnostics` at the place where they are detected,
+// so `Result<T, ()>` is all the caller needs to know about them. Operations that
+// don't have access to the `Diagnostics` return `Fallible` and let the caller report it.
+#![allow(clippy::result_unit_err)]
+
+pub mod lexer;
+pub mod expr;
+pub mod set_expr;
+pub mod dispatch;
+pub mod overlap;
+pub mod json;
+pub mod debugger;
+pub mod diagnostics;
+pub mod lsp;
+pub mod formatter;
+pub mod graph;
+
+use std::result;
+use std::fmt::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ops::{Index, IndexMut};
+use std::sync::{Mutex, OnceLock};
+use unicode_width::UnicodeWidthStr;
+
+use lexer::*;
+use expr::*;
+use set_expr::*;
+use dispatch::*;
+use diagnostics::*;
+
+pub type Result<T> = result::Result<T, ()>;
+
+#[derive(Debug, Clone)]
+pub struct Case<'nsa> {
+    pub keyword: Symbol<'nsa>,
+    pub state: Expr<'nsa>,
+    pub read: Expr<'nsa>,
+    pub write: Expr<'nsa>,
+    pub step: Expr<'nsa>,
+    pub next: Expr<'nsa>,
+}
+
+impl<'nsa> fmt::Display for Case<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Case{keyword, state, read, write, step, next} = self;
+        write!(f, "{keyword} {state} {read} {write} {step} {next}")
+    }
+}
+
+impl<'nsa> Case<'nsa> {
+    fn parse(lexer: &mut Lexer<'nsa>, keyword: Symbol<'nsa>) -> Result<Self> {
+        let state = Expr::parse(lexer)?;
+        let read  = Expr::parse(lexer)?;
+        let write = Expr::parse(lexer)?;
+        let step  = Expr::parse(lexer)?;
+        let next  = Expr::parse(lexer)?;
+        Ok(Case{keyword, state, read, write, step, next})
+    }
+
+    pub fn substitute_bindings(&self, bindings: &HashMap<Symbol<'nsa>, Expr<'nsa>>) -> Self {
+        let Case{keyword, state, read, write, step, next} = self;
+        let state = state.substitute_bindings(bindings);
+        let read  = read.substitute_bindings(bindings);
+        let write = write.substitute_bindings(bindings);
+        let step  = step.substitute_bindings(bindings);
+        let next  = next.substitute_bindings(bindings);
+        let keyword = *keyword;
+        Case{keyword, state, read, write, step, next}
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Statement<'nsa> {
+    Case(Box<Case<'nsa>>),
+    Block {
+        open: Symbol<'nsa>,
+        statements: Vec<Statement<'nsa>>,
+        close: Symbol<'nsa>,
+    },
+    /// Universal Quantifier. The `pattern` is either a single variable or a tuple that
+    /// destructures the elements of the `set`. Elements that do not match it are skipped.
+    /// `for a b in <set>` is parsed into nested quantifiers that share the `keyword`.
+    For {
+        keyword: Symbol<'nsa>,
+        pattern: Expr<'nsa>,
+        set: SetExpr<'nsa>,
+        body: Box<Statement<'nsa>>,
+    },
+    /// Declares the States matching the pattern as the ones the Machine halts in.
+    Halt {
+        keyword: Symbol<'nsa>,
+        state: Expr<'nsa>,
+    },
+}
+
+impl<'nsa> fmt::Display for Statement<'nsa> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Block{statements, ..} => {
+                write!(f, "{{")?;
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{statement}")?;
+                }
+                write!(f, "}}")
+            }
+            Self::Case(case) => write!(f, "{case}"),
+            Self::For{pattern, set, body, ..} => {
+                write!(f, "for {pattern} in {set} {body}")
+            }
+            Self::Halt{keyword, state} => write!(f, "{keyword} {state}"),
+        }
+    }
+}
+
+/// What a variable of a Universal Quantifier ranges over: the `pattern` it was bound by (just
+/// the variable itself unless it was destructured from a tuple) must be an element of the `set`.
+#[derive(Debug, Clone)]
+pub struct Quantifier<'nsa> {
+    pub pattern: Expr<'nsa>,
+    pub set: SetExpr<'nsa>,
+}
+
+pub type Scope<'nsa> = HashMap<Symbol<'nsa>, Quantifier<'nsa>>;
+
+/// Collects the variables of the pattern of a Universal Quantifier in the order they appear.
+pub(crate) fn pattern_vars<'nsa>(pattern: &Expr<'nsa>, vars: &mut Vec<Symbol<'nsa>>) {
+    match pattern {
+        Expr::Atom(Atom::Symbol(symbol)) => vars.push(*symbol),
+        Expr::Atom(_) | Expr::Eval{..} => {}
+        Expr::Tuple{elements, ..} => {
+            for element in elements {
+                pattern_vars(element, vars);
+            }
+        }
+    }
+}
+
+/// Finds the variables of the scope that can't be bound by matching the input. A variable
+/// destructured from a tuple is bound as long as any other variable of the same tuple is,
+/// because the rest of the tuple is then looked up in the set.
+fn unused_vars<'a, 'nsa, F>(scope: &'a Scope<'nsa>, used: F) -> Vec<&'a Symbol<'nsa>> where F: Fn(&Symbol<'nsa>) -> bool {
+    scope.iter().filter(|(var, Quantifier{pattern, ..})| {
+        if used(var) {
+            return false
+        }
+        let mut vars = vec![];
+        pattern_vars(pattern, &mut vars);
+        !vars.iter().any(&used)
+    }).map(|(var, _)| var).collect()
+}
+
+/// Reports the variables destructured from a tuple that the input does not use if their sets
+/// can't be expanded, since they are looked up among the elements of the set at runtime.
+fn check_destructured_vars<'nsa, F>(scope: &Scope<'nsa>, sets: &Sets<'nsa>, used: F, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> where F: Fn(&Symbol<'nsa>) -> bool {
+    let mut vars: Vec<_> = scope.iter().filter(|(var, _)| !used(var)).collect();
+    vars.sort_by_key(|(var, _)| *var);
+    let mut result = Ok(());
+    for (var, Quantifier{set, ..}) in vars {
+        if let Err(diagnostic) = set.expand(sets) {
+            diagnostics.report(diagnostic.note(var.loc, format!("{var} is not used in the input, so it has to be looked up in the set")));
+            result = Err(())
+        }
+    }
+    result
+}
+
+impl<'nsa> Statement<'nsa> {
+    fn parse(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
+        let key = lexer.expect_symbols(&["case", "for", "halt", "{"])?;
+        match key.name {
+            "case" => Ok(Statement::Case(Box::new(Case::parse(lexer, key)?))),
+            "halt" => Ok(Statement::Halt{keyword: key, state: Expr::parse(lexer)?}),
+            "{" => {
+                let mut statements = vec![];
+                while let Some(symbol) = lexer.peek_symbol() {
+                    if symbol.name == "}" {
+                        break;
+                    }
+                    statements.push(Statement::parse(lexer, sets)?);
+                }
+                let close = lexer.expect_symbols(&["}"])?;
+                Ok(Statement::Block{open: key, statements, close})
+            }
+            "for" => {
+                let mut patterns = vec![];
+                while let Some(symbol) = lexer.peek_symbol() {
+                    if symbol.name == "in" {
+                        break;
+                    }
+                    let expr = Expr::parse(lexer)?;
+                    match &expr {
+                        Expr::Atom(Atom::Symbol(_)) => {}
+                        Expr::Atom(atom) => {
+                            lexer.diagnostics.report(Diagnostic::error(Code::TypeMismatch, *atom.loc(), format!("{human} may not be used as variable names", human = atom.human())));
+                            return Err(())
+                        }
+                        _ => if let Some(eval) = expr.first_eval() {
+                            lexer.diagnostics.report(Diagnostic::error(Code::EvalInPattern, *eval.loc(), "Eval Expressions may not be used in the patterns of Universal Quantifiers"));
+                            return Err(())
+                        }
+                    }
+                    patterns.push(expr);
+                }
+                let _ = lexer.expect_symbols(&["in"])?;
+                let set = SetExpr::parse(lexer, sets)?;
+                let mut result = Statement::parse(lexer, sets)?;
+                for pattern in patterns.into_iter().rev() {
+                    result = Statement::For{
+                        keyword: key,
+                        pattern,
+                        set: set.clone(),
+                        body: Box::new(result)
+                    }
+                }
+                Ok(result)
+            }
+            _ => unreachable!()
+        }
+    }
+
+    fn visit_leaves<F>(&self, scope: &mut Scope<'nsa>, f: &mut F) where F: FnMut(&Statement<'nsa>, &Scope<'nsa>) {
+        match self {
+            Statement::Case(_) | Statement::Halt{..} => f(self, scope),
+            Statement::Block{statements, ..} => {
+                for statement in statements {
+                    statement.visit_leaves(scope, f);
+                }
+            }
+            Statement::For{pattern, set, body, ..} => {
+                let mut vars = vec![];
+                pattern_vars(pattern, &mut vars);
+                for var in &vars {
+                    let quantifier = Quantifier{pattern: pattern.clone(), set: set.clone()};
+                    let shadowed = scope.insert(*var, quantifier).is_some();
+                    assert!(!shadowed, "A variable is shadowed while visiting statements. Sanity check was not performed before.");
+                }
+                body.visit_leaves(scope, f);
+                for var in &vars {
+                    scope.remove(var);
+                }
+            }
+        }
+    }
+
+    /// Calls `f` for each case of the statement in the source order together with the scope of
+    /// the Universal Quantifiers it is nested in.
+    pub fn visit_cases<F>(&self, scope: &mut Scope<'nsa>, f: &mut F) where F: FnMut(&Case<'nsa>, &Scope<'nsa>) {
+        self.visit_leaves(scope, &mut |statement, scope| {
+            if let Statement::Case(case) = statement {
+                f(case, scope)
+            }
+        })
+    }
+
+    /// Calls `f` for the State pattern of each `halt` declaration of the statement in the source
+    /// order together with the scope of the Universal Quantifiers it is nested in.
+    pub fn visit_halts<F>(&self, scope: &mut Scope<'nsa>, f: &mut F) where F: FnMut(&Expr<'nsa>, &Scope<'nsa>) {
+        self.visit_leaves(scope, &mut |statement, scope| {
+            if let Statement::Halt{state, ..} = statement {
+                f(state, scope)
+            }
+        })
+    }
+
+    fn expand_bound(&self, bindings: &mut HashMap<Symbol<'nsa>, Expr<'nsa>>, sets: &Sets<'nsa>, result: &mut Vec<Statement<'nsa>>) -> Fallible<'nsa, ()> {
+        match self {
+            Statement::Case(case) => {
+                let mut case = case.substitute_bindings(bindings);
+                case.write = case.write.force_evals()?;
+                case.step = case.step.force_evals()?;
+                case.next = case.next.force_evals()?;
+                result.push(Statement::Case(Box::new(case)));
+            }
+            Statement::Halt{keyword, state} => {
+                result.push(Statement::Halt{keyword: *keyword, state: state.substitute_bindings(bindings)});
+            }
+            Statement::For{pattern, set, body, ..} => {
+                let mut vars = vec![];
+                pattern_vars(pattern, &mut vars);
+                let scope: Scope<'nsa> = vars.iter().map(|var| (*var, Quantifier{pattern: pattern.clone(), set: set.clone()})).collect();
+                for element in set.expand(sets)?.iter() {
+                    let mut destructured = HashMap::new();
+                    if !pattern.pattern_match(element, &scope, &mut destructured) {
+                        continue
+                    }
+                    for (var, value) in destructured {
+                        let shadowed = bindings.insert(var, value).is_some();
+                        assert!(!shadowed, "A variable is shadowed at expansion. Sanity check was not performed before execution.");
+                    }
+                    body.expand_bound(bindings, sets, result)?;
+                    for var in &vars {
+                        bindings.remove(var);
+                    }
+                }
+            }
+            Statement::Block{statements, ..} => {
+                for statement in statements {
+                    statement.expand_bound(bindings, sets, result)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands all the Universal Quantifiers of the statement into plain cases with
+    /// their Write, Step and Next already forced and plain `halt` declarations.
+    pub fn expand(&self, sets: &Sets<'nsa>) -> Fallible<'nsa, Vec<Statement<'nsa>>> {
+        let mut bindings = HashMap::new();
+        let mut result = vec![];
+        self.expand_bound(&mut bindings, sets, &mut result)?;
+        Ok(result)
+    }
+
+    fn sanity_check_scoped(&self, sets: &Sets<'nsa>, scope: &mut Scope<'nsa>, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> {
+        match self {
+            Statement::Case(case) => {
+                let mut result = Ok(());
+                for expr in [&case.state, &case.read] {
+                    if let Some(eval) = expr.first_eval() {
+                        diagnostics.report(Diagnostic::error(Code::EvalInPattern, *eval.loc(), "Eval Expressions may not be used in the State and Read of a case"));
+                        result = Err(())
+                    }
+                }
+                result?;
+                let used = |var: &Symbol<'nsa>| case.state.uses_var(var).or_else(|| case.read.uses_var(var)).is_some();
+                let mut unused_vars = unused_vars(scope, used);
+                if !unused_vars.is_empty() {
+                    let mut diagnostic = Diagnostic::error(Code::UnusedVariable, case.keyword.loc, "not all variables in the scope are used in the input of the case");
+                    unused_vars.sort();
+                    for var in unused_vars {
+                        diagnostic = diagnostic.note(var.loc, format!("unused variable {var}"));
+                    }
+                    diagnostics.report(diagnostic);
+                    return Err(())
+                }
+                check_destructured_vars(scope, sets, used, diagnostics)?;
+            }
+            Statement::Halt{keyword, state} => {
+                if let Some(eval) = state.first_eval() {
+                    diagnostics.report(Diagnostic::error(Code::EvalInPattern, *eval.loc(), "Eval Expressions may not be used in halt states"));
+                    return Err(())
+                }
+                let used = |var: &Symbol<'nsa>| state.uses_var(var).is_some();
+                let mut unused_vars = unused_vars(scope, used);
+                if !unused_vars.is_empty() {
+                    let mut diagnostic = Diagnostic::error(Code::UnusedVariable, keyword.loc, "not all variables in the scope are used in the halt state");
+                    unused_vars.sort();
+                    for var in unused_vars {
+                        diagnostic = diagnostic.note(var.loc, format!("unused variable {var}"));
+                    }
+                    diagnostics.report(diagnostic);
+                    return Err(())
+                }
+                check_destructured_vars(scope, sets, used, diagnostics)?;
+            }
+            Statement::Block{statements, ..} => {
+                let mut result = Ok(());
+                for statement in statements {
+                    if statement.sanity_check_scoped(sets, scope, diagnostics).is_err() {
+                        result = Err(())
+                    }
+                }
+                return result
+            }
+            Statement::For{pattern, set, body, ..} => {
+                let mut vars = vec![];
+                pattern_vars(pattern, &mut vars);
+                for (i, var) in vars.iter().enumerate() {
+                    if let Some((shadowed_var, _)) = scope.get_key_value(var) {
+                        diagnostics.report(Diagnostic::error(Code::Shadowing, var.loc, format!("{var} shadows another name in the higher scope"))
+                            .note(shadowed_var.loc, "the shadowed name is located here"));
+                        return Err(())
+                    }
+                    if let Some(bound_var) = vars[..i].iter().find(|bound_var| *bound_var == var) {
+                        diagnostics.report(Diagnostic::error(Code::Shadowing, var.loc, format!("{var} is bound more than once by the same pattern"))
+                            .note(bound_var.loc, "it is first bound here"));
+                        return Err(())
+                    }
+                }
+                for var in &vars {
+                    scope.insert(*var, Quantifier{pattern: pattern.clone(), set: set.clone()});
+                }
+                let result = body.sanity_check_scoped(sets, scope, diagnostics);
+                for var in &vars {
+                    scope.remove(var);
+                }
+                return result
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports all the problems of the statement, not just the first one.
+    pub fn sanity_check(&self, sets: &Sets<'nsa>, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> {
+        let mut scope = Scope::new();
+        self.sanity_check_scoped(sets, &mut scope, diagnostics)
+    }
+}
+
+/// Infinite in both directions Tape of the Machine.
+///
+/// `right` holds the cells at indices `0, 1, 2, ...` and `left` holds the cells at
+/// indices `-1, -2, -3, ...` (so `left` is stored in reverse order). Everything beyond
+/// them is filled with `left_default` and `right_default` correspondingly.
+#[derive(Debug, Clone)]
+pub struct Tape<'nsa> {
+    pub left: Vec<Expr<'nsa>>,
+    left_default: Expr<'nsa>,
+    pub right: Vec<Expr<'nsa>>,
+    right_default: Expr<'nsa>,
+}
+
+impl<'nsa> Tape<'nsa> {
+    /// Creates the Tape from the cells to the left of the head (in reverse order) and the
+    /// cells starting at the head. Returns `None` if both are empty since we don't know
+    /// what to fill the rest of the Tape with. If only `right` is empty the head starts at
+    /// a cell filled with `right_default`.
+    pub fn new(left: Vec<Expr<'nsa>>, mut right: Vec<Expr<'nsa>>) -> Option<Self> {
+        let left_default = left.first().or(right.first())?.clone();
+        let right_default = right.last().or(left.last())?.clone();
+        if right.is_empty() {
+            right.push(right_default.clone());
+        }
+        Some(Self {
+            left, left_default,
+            right, right_default,
+        })
+    }
+
+    /// Parses the whole source as a sequence of cells separated by whitespaces. The head starts
+    /// at the cell that follows the standalone `^` symbol or at the first cell if there is none.
+    pub fn parse_source(source: &'nsa str, file_path: &'nsa str) -> Result<Self> {
+        Self::parse(&mut Lexer::new(source, file_path))
+    }
+
+    /// Parses the rest of the source of the lexer as in [`Tape::parse_source`].
+    pub fn parse(lexer: &mut Lexer<'nsa>) -> Result<Self> {
+        let mut left = vec![];
+        let mut right = vec![];
+        let mut marker: Option<Symbol<'nsa>> = None;
+        while let Some(symbol) = lexer.peek_symbol() {
+            if symbol.name == "^" {
+                if let Some(marker) = marker {
+                    lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, symbol.loc, "the position of the head is marked more than once")
+                        .note(marker.loc, "first marked here"));
+                    return Err(())
+                }
+                lexer.next_symbol();
+                left = std::mem::take(&mut right);
+                marker = Some(symbol);
+                continue;
+            }
+            let cell = Expr::parse(lexer)?;
+            right.push(lexer.diagnostics.report_err(cell.force_evals())?);
+        }
+        if let Some(marker) = marker {
+            if right.is_empty() {
+                lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, marker.loc, "there is no cell for the head after the marker"));
+                return Err(())
+            }
+        }
+        left.reverse();
+        Self::new(left, right).ok_or_else(|| {
+            let loc = lexer.loc();
+            lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, loc, "The tape may not be empty. It must contain at least one symbol so we know what to fill it with"));
+        })
+    }
+
+    /// Materializes the cells up to the index. Returns the amount of the new cells.
+    fn touch(&mut self, index: i32) -> usize {
+        let (cells, default, index) = if index >= 0 {
+            (&mut self.right, &self.right_default, index as usize)
+        } else {
+            (&mut self.left, &self.left_default, (index.abs() - 1) as usize)
+        };
+        let len = cells.len();
+        while index >= cells.len() {
+            cells.push(default.clone());
+        }
+        cells.len() - len
+    }
+
+    /// Reverts [`Tape::touch`] by removing the `count` outermost cells on the side of the index.
+    fn untouch(&mut self, index: i32, count: usize) {
+        let cells = if index >= 0 { &mut self.right } else { &mut self.left };
+        cells.truncate(cells.len() - count);
+    }
+
+    /// Iterates all the materialized cells of the Tape from left to right together with their indices.
+    pub fn cells(&self) -> impl Iterator<Item = (i32, &Expr<'nsa>)> {
+        self.left
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (-(i as i32 + 1), x))
+            .rev()
+            .chain(self.right.iter().enumerate().map(|(i, x)| (i as i32, x)))
+    }
+}
+
+impl<'nsa> Index<i32> for Tape<'nsa> {
+    type Output = Expr<'nsa>;
+    fn index(&self, index: i32) -> &Expr<'nsa> {
+        if index >= 0 {
+            let index = index as usize;
+            if index >= self.right.len() {
+                &self.right_default
+            } else {
+                &self.right[index]
+            }
+        } else {
+            let index = (index.abs() - 1) as usize;
+            if index >= self.left.len() {
+                &self.left_default
+            } else {
+                &self.left[index]
+            }
+        }
+    }
+}
+
+impl<'nsa> IndexMut<i32> for Tape<'nsa> {
+    fn index_mut(&mut self, index: i32) -> &mut Expr<'nsa> {
+        if index >= 0 {
+            let index = index as usize;
+            &mut self.right[index]
+        } else {
+            let index = (index.abs() - 1) as usize;
+            &mut self.left[index]
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Left,
+    Right,
+    Stay,
+    Print,
+}
+
+impl Action {
+    pub fn from_symbol<'nsa>(symbol: &Symbol<'nsa>) -> Fallible<'nsa, Self> {
+        match symbol.name {
+            "<-" => Ok(Action::Left),
+            "->" => Ok(Action::Right),
+            "." => Ok(Action::Stay),
+            "!" => Ok(Action::Print),
+            _ => Err(Diagnostic::error(Code::UnexpectedSymbol, symbol.loc, format!("unknown step action {symbol}"))),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Left => write!(f, "<-"),
+            Action::Right => write!(f, "->"),
+            Action::Stay => write!(f, "."),
+            Action::Print => write!(f, "!"),
+        }
+    }
+}
+
+/// A single step performed by the Machine.
+#[derive(Debug, Clone)]
+pub struct Transition<'nsa> {
+    /// The location of the case that fired.
+    pub case: Loc<'nsa>,
+    /// The values of the variables of the case that fired.
+    pub bindings: HashMap<Symbol<'nsa>, Expr<'nsa>>,
+    /// The state the Machine was in before the step.
+    pub state: Expr<'nsa>,
+    /// The position of the head before the step.
+    pub head: i32,
+    pub read: Expr<'nsa>,
+    pub write: Expr<'nsa>,
+    pub action: Action,
+    pub next: Expr<'nsa>,
+}
+
+/// Everything needed to revert a single step of the Machine.
+#[derive(Debug, Clone)]
+struct Undo<'nsa> {
+    /// The state before the step.
+    state: Expr<'nsa>,
+    /// The value of the cell under the head before the step.
+    read: Expr<'nsa>,
+    /// How far the head moved.
+    delta: i8,
+    /// Amount of cells the step materialized on the Tape.
+    grown: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Machine<'nsa> {
+    pub state: Expr<'nsa>,
+    pub tape: Tape<'nsa>,
+    pub head: i32,
+    pub halt: bool,
+    /// Amount of steps performed so far.
+    pub steps: usize,
+    /// The undo log of the performed steps if it is enabled by [`Machine::record_undo`].
+    undo: Option<Vec<Undo<'nsa>>>,
+}
+
+impl<'nsa> Machine<'nsa> {
+    pub fn new(state: Expr<'nsa>, tape: Tape<'nsa>) -> Self {
+        Self {
+            state,
+            tape,
+            head: 0,
+            halt: false,
+            steps: 0,
+            undo: None,
+        }
+    }
+
+    pub fn from_run(run: &Run<'nsa>) -> Self {
+        Self::new(run.state.clone(), run.tape.clone())
+    }
+
+    /// Performs a single step of the Machine according to the first case of the program that matches
+    /// the current state and the value under the head. The `!` action does not print anything by
+    /// itself, it's up to the caller to react on it.
+    ///
+    /// Returns `None` and halts the Machine if it is in one of the declared halt states. If the program
+    /// does not declare any halt states the Machine halts when no case matches, otherwise getting stuck
+    /// in a State that is not declared as halting is an error.
+    pub fn next(&mut self, dispatch: &Dispatch<'nsa>) -> Fallible<'nsa, Option<Transition<'nsa>>> {
+        if self.halt {
+            return Ok(None)
+        }
+        if dispatch.declares_halts() && dispatch.is_halt(&self.state) {
+            self.halt = true;
+            return Ok(None)
+        }
+        let Some(Outcome{keyword, bindings, write, step, next}) = dispatch.match_next_case(&self.state, &self.tape[self.head])? else {
+            if dispatch.declares_halts() {
+                return Err(Diagnostic::error(Code::Stuck, *self.state.loc(), format!("the machine got stuck in state {state} reading {read}, but the state is not declared as a halt state",
+                                                                         state = self.state, read = self.tape[self.head])))
+            }
+            self.halt = true;
+            return Ok(None)
+        };
+        let action = Action::from_symbol(step.expect_atom()?.expect_symbol()?)?;
+        let write = write.force_evals()?;
+        let read = std::mem::replace(&mut self.tape[self.head], write.clone());
+        let head = self.head;
+        let delta = match action {
+            Action::Left => -1,
+            Action::Right => 1,
+            Action::Stay | Action::Print => 0,
+        };
+        self.head += i32::from(delta);
+        let grown = self.tape.touch(self.head);
+        let state = std::mem::replace(&mut self.state, next.clone());
+        self.steps += 1;
+        if let Some(undo) = &mut self.undo {
+            undo.push(Undo{state: state.clone(), read: read.clone(), delta, grown});
+        }
+        Ok(Some(Transition{case: keyword.loc, bindings, state, head, read, write, action, next}))
+    }
+
+    /// Starts recording the undo log of the following steps so they can be reverted with
+    /// [`Machine::step_back`].
+    pub fn record_undo(&mut self) {
+        self.undo.get_or_insert_with(Vec::new);
+    }
+
+    /// Reverts the last recorded step. Returns `false` if there is nothing to revert.
+    pub fn step_back(&mut self) -> bool {
+        let Some(Undo{state, read, delta, grown}) = self.undo.as_mut().and_then(|undo| undo.pop()) else {
+            return false
+        };
+        self.halt = false;
+        self.tape.untouch(self.head, grown);
+        self.head -= i32::from(delta);
+        self.tape[self.head] = read;
+        self.state = state;
+        self.steps -= 1;
+        true
+    }
+
+    /// Renders all the materialized cells of the Tape separated by spaces.
+    pub fn render_tape(&self) -> String {
+        let mut buffer = String::new();
+        for (_, expr) in self.tape.cells() {
+            let _ = write!(&mut buffer, "{expr} ");
+        }
+        buffer
+    }
+
+    /// Renders the current state and the Tape on the first line and the caret pointing
+    /// at the head on the second one. Takes the width of Unicode characters into account.
+    pub fn render_trace(&self) -> String {
+        self.render_trace_window(None)
+    }
+
+    /// Same as [`Machine::render_trace`] but only renders the cells that are at most `radius`
+    /// cells away from the head if the `radius` is provided.
+    pub fn render_trace_window(&self, radius: Option<usize>) -> String {
+        let mut buffer = String::new();
+        let _ = write!(&mut buffer, "{state}: ", state = self.state);
+        let mut head_begin = 0;
+        let mut head_end = 0;
+        let cells = self.tape.cells().filter(|(i, _)| radius.is_none_or(|radius| i.abs_diff(self.head) as usize <= radius));
+        for (j, (i, expr)) in cells.enumerate() {
+            if j > 0 {
+                let _ = write!(&mut buffer, " ");
+            }
+            if i == self.head {
+                head_begin = buffer.len();
+            }
+            let _ = write!(&mut buffer, "{expr}");
+            if i == self.head {
+                head_end = buffer.len();
+            }
+        }
+        //                     head_end
+        //                     v
+        // "State: aaa bbb cccc dddd"
+        //                 ^
+        //                 head_begin
+        let mut caret = String::new();
+        let _ = write!(&mut caret, "{pad:width$}", pad = "", width = UnicodeWidthStr::width(&buffer[0..head_begin]));
+        let _ = write!(&mut caret, "{x:~<width$}", x = "^", width = UnicodeWidthStr::width(&buffer[head_begin..head_end]));
+        buffer.push('\n');
+        buffer.push_str(&caret);
+        buffer
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunKind {
+    Run,
+    Trace,
+}
+
+impl RunKind {
+    fn from_name(name: &str) -> Option<RunKind> {
+        match name {
+            "run" => Some(RunKind::Run),
+            "trace" => Some(RunKind::Trace),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for RunKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunKind::Run => write!(f, "run"),
+            RunKind::Trace => write!(f, "trace"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Run<'nsa> {
+    pub kind: RunKind,
+    pub keyword: Symbol<'nsa>,
+    pub state: Expr<'nsa>,
+    pub tape: Tape<'nsa>,
+    pub written: Written<'nsa>,
+}
+
+/// A sequence of cells in curly braces as it was written in the source.
+#[derive(Debug, Clone)]
+pub struct Cells<'nsa> {
+    pub open: Symbol<'nsa>,
+    pub cells: Vec<Expr<'nsa>>,
+    pub close: Symbol<'nsa>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TapeSource<'nsa> {
+    /// `@'<tape file>'`
+    File(Symbol<'nsa>),
+    /// `{ <cells> }` or `{ <left cells> } { <cells from the head> }`
+    Inline(Vec<Cells<'nsa>>),
+}
+
+/// The configuration of the Machine as it was written in the source: the Eval Expressions are
+/// not forced and the tape files are not read. Used by the [`formatter`].
+#[derive(Debug, Clone)]
+pub struct Written<'nsa> {
+    pub state: Expr<'nsa>,
+    pub tape: TapeSource<'nsa>,
+}
+
+impl<'nsa> Run<'nsa> {
+    fn parse(lexer: &mut Lexer<'nsa>) -> Result<Self> {
+        let keyword = lexer.expect_symbols(&["run", "trace"])?;
+        let kind = RunKind::from_name(keyword.name).unwrap();
+        let (state, tape, written) = Self::parse_config(lexer)?;
+        Ok(Run {keyword, state, tape, kind, written})
+    }
+
+    /// Parses `<state> <tape>` forcing the Eval Expressions of the state.
+    fn parse_config(lexer: &mut Lexer<'nsa>) -> Result<(Expr<'nsa>, Tape<'nsa>, Written<'nsa>)> {
+        let written_state = Expr::parse(lexer)?;
+        let state = lexer.diagnostics.report_err(written_state.clone().force_evals())?;
+        let (tape, written_tape) = Self::parse_tape(lexer)?;
+        Ok((state, tape, Written{state: written_state, tape: written_tape}))
+    }
+
+    /// Parses `{ <cells> }`, `{ <left cells> } { <cells from the head> }` or `@'<tape file>'`.
+    fn parse_tape(lexer: &mut Lexer<'nsa>) -> Result<(Tape<'nsa>, TapeSource<'nsa>)> {
+        if let Some(at) = lexer.peek_symbol().filter(|symbol| symbol.name == "@") {
+            lexer.next_symbol();
+            let symbol = lexer.parse_symbol()?;
+            let atom = Atom::from_symbol(symbol)?;
+            let file_path = resolve_path(&at.loc, lexer.diagnostics.report_err(atom.expect_string())?);
+            let (source, file_path) = lexer.diagnostics.report_err(read_leaked(atom.loc(), &file_path))?;
+            let tape = lexer.nested(source, file_path, Tape::parse)?;
+            return Ok((tape, TapeSource::File(symbol)))
+        }
+        let (seq, mut tape_seq) = Self::parse_tape_seq(lexer)?;
+        let open_curly_of_tape_seq = seq.open;
+        let mut seqs = vec![seq];
+        if let Some(symbol) = lexer.peek_symbol() {
+            if symbol.name == "{" {
+                let (seq_right, tape_seq_right) = Self::parse_tape_seq(lexer)?;
+                seqs.push(seq_right);
+                tape_seq.reverse();
+                if let Some(tape) = Tape::new(tape_seq, tape_seq_right) {
+                    return Ok((tape, TapeSource::Inline(seqs)))
+                } else {
+                    lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, open_curly_of_tape_seq.loc, "The tape may not be empty. It must contain at least one symbol so we know what to fill it with"));
+                    return Err(());
+                }
+            }
+        }
+        if let Some(tape) = Tape::new(vec![], tape_seq) {
+            Ok((tape, TapeSource::Inline(seqs)))
+        } else {
+            lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, open_curly_of_tape_seq.loc, "The tape may not be empty. It must contain at least one symbol so we know what to fill it with"));
+            Err(())
+        }
+    }
+
+    /// Returns the cells as they were written together with the forced ones.
+    fn parse_tape_seq(lexer: &mut Lexer<'nsa>) -> Result<(Cells<'nsa>, Vec<Expr<'nsa>>)> {
+        let open = lexer.expect_symbols(&["{"])?;
+        let mut cells = vec![];
+        let mut seq = vec![];
+        while let Some(symbol) = lexer.peek_symbol() {
+            if symbol.name == "}" {
+                break;
+            }
+            let cell = Expr::parse(lexer)?;
+            seq.push(lexer.diagnostics.report_err(cell.clone().force_evals())?);
+            cells.push(cell);
+        }
+        let close = lexer.expect_symbols(&["}"])?;
+        Ok((Cells{open, cells, close}, seq))
+    }
+}
+
+/// `test <state> <tape> expect <state> <tape> [head <index>] [max-steps <N>]`
+///
+/// Runs the Machine from the first configuration and checks that it halts in the expected one.
+/// The cells of both tapes are compared by their indices, where `0` is the cell the head starts at.
+#[derive(Debug, Clone)]
+pub struct Test<'nsa> {
+    pub keyword: Symbol<'nsa>,
+    pub state: Expr<'nsa>,
+    pub tape: Tape<'nsa>,
+    pub expected_state: Expr<'nsa>,
+    pub expected_tape: Tape<'nsa>,
+    /// The expected position of the head, not checked if not provided.
+    pub expected_head: Option<i32>,
+    /// The Machine must halt within that amount of steps if provided.
+    pub max_steps: Option<usize>,
+    pub written: Written<'nsa>,
+    pub expect: Symbol<'nsa>,
+    pub expected_written: Written<'nsa>,
+}
+
+impl<'nsa> Test<'nsa> {
+    fn parse(lexer: &mut Lexer<'nsa>) -> Result<Self> {
+        let keyword = lexer.expect_symbols(&["test"])?;
+        let (state, tape, written) = Run::parse_config(lexer)?;
+        let expect = lexer.expect_symbols(&["expect"])?;
+        let (expected_state, expected_tape, expected_written) = Run::parse_config(lexer)?;
+        let mut expected_head = None;
+        let mut max_steps = None;
+        while let Some(symbol) = lexer.peek_symbol() {
+            match symbol.name {
+                "head" if expected_head.is_none() => {
+                    lexer.next_symbol();
+                    let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+                    let head = lexer.diagnostics.report_err(atom.expect_integer())?;
+                    expected_head = Some(i32::try_from(head).map_err(|_| {
+                        lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, *atom.loc(), format!("head position {head} is out of bounds")));
+                    })?);
+                }
+                "max-steps" if max_steps.is_none() => {
+                    lexer.next_symbol();
+                    let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+                    let steps = lexer.diagnostics.report_err(atom.expect_integer())?;
+                    max_steps = Some(usize::try_from(steps).map_err(|_| {
+                        lexer.diagnostics.report(Diagnostic::error(Code::InvalidTape, *atom.loc(), "amount of steps may not be negative"));
+                    })?);
+                }
+                _ => break,
+            }
+        }
+        Ok(Test{keyword, state, tape, expected_state, expected_tape, expected_head, max_steps, written, expect, expected_written})
+    }
+
+    /// Runs the Machine until it halts or reaches [`Test::max_steps`].
+    pub fn run(&self, dispatch: &Dispatch<'nsa>) -> Fallible<'nsa, Machine<'nsa>> {
+        let mut machine = Machine::new(self.state.clone(), self.tape.clone());
+        loop {
+            if self.max_steps.is_some_and(|max_steps| machine.steps >= max_steps) {
+                // The Machine that stops right after the last allowed step still halts within the limit.
+                if machine.clone().next(dispatch)?.is_none() {
+                    machine.halt = true;
+                }
+                break
+            }
+            if machine.next(dispatch)?.is_none() {
+                break
+            }
+        }
+        Ok(machine)
+    }
+
+    /// The range of the cell indices that covers both the expected tape and the actual one.
+    pub fn tape_range(&self, tape: &Tape<'nsa>) -> std::ops::RangeInclusive<i32> {
+        let (first, last) = tape.cells().chain(self.expected_tape.cells()).fold((0, 0), |(first, last), (i, _)| (first.min(i), last.max(i)));
+        first..=last
+    }
+
+    /// Whether the Machine halted in the expected configuration.
+    pub fn passed(&self, machine: &Machine<'nsa>) -> bool {
+        machine.halt
+            && machine.state == self.expected_state
+            && self.expected_head.is_none_or(|head| head == machine.head)
+            && self.tape_range(&machine.tape).all(|i| machine.tape[i] == self.expected_tape[i])
+    }
+}
+
+/// Parsed Tula program: the set definitions, the statements with all the cases, the
+/// `run`/`trace` entry points and the `test`s in the order they appear in the source.
+#[derive(Debug, Clone, Default)]
+pub struct Program<'nsa> {
+    pub sets: Sets<'nsa>,
+    pub statements: Vec<Statement<'nsa>>,
+    pub runs: Vec<Run<'nsa>>,
+    pub tests: Vec<Test<'nsa>>,
+    /// The top level constructs of the root file in the order they appear in the source.
+    pub items: Vec<Item<'nsa>>,
+}
+
+/// A top level construct of the root file of a [`Program`]. The constructs that live in the
+/// other fields of the program are referred to by their indices.
+#[derive(Debug, Clone)]
+pub enum Item<'nsa> {
+    Include {
+        keyword: Symbol<'nsa>,
+        path: Symbol<'nsa>,
+    },
+    /// The set is in [`Program::sets`] under the `name`.
+    Let {
+        keyword: Symbol<'nsa>,
+        name: Symbol<'nsa>,
+    },
+    Statement(usize),
+    Run(usize),
+    Test(usize),
+}
+
+/// Resolves the path found in the source relative to the directory of the file it was found in.
+fn resolve_path(loc: &Loc, relative_path: &str) -> PathBuf {
+    Path::new(loc.file_path).parent().unwrap_or(Path::new("")).join(relative_path)
+}
+
+/// The source and the path of a file.
+type Leaked = (&'static str, &'static str);
+
+/// Reads the file referred to at `loc` from within a program. The source and the path of the
+/// file are leaked to outlive the Program that refers to them.
+fn read_leaked<'nsa>(loc: &Loc<'nsa>, file_path: &Path) -> Fallible<'nsa, (&'nsa str, &'nsa str)> {
+    let source = fs::read_to_string(file_path).map_err(|err| {
+        Diagnostic::error(Code::Include, *loc, format!("could not read file {file_path}: {err}", file_path = file_path.display()))
+    })?;
+    // The Language Server parses the same files on every edit, so each version of a file is
+    // only leaked once
+    static LEAKED: OnceLock<Mutex<HashMap<PathBuf, Vec<Leaked>>>> = OnceLock::new();
+    let mut leaked = LEAKED.get_or_init(Default::default).lock().unwrap_or_else(|err| err.into_inner());
+    let versions = leaked.entry(file_path.to_path_buf()).or_default();
+    if let Some(version) = versions.iter().find(|(leaked_source, _)| *leaked_source == source) {
+        return Ok(*version)
+    }
+    let source: &'static str = Box::leak(source.into_boxed_str());
+    let file_path: &'static str = Box::leak(file_path.display().to_string().into_boxed_str());
+    versions.push((source, file_path));
+    Ok((source, file_path))
+}
+
+/// Keeps track of the files included while parsing a program.
+struct Includes<'nsa> {
+    /// The files that are being parsed right now together with the `include` statements that
+    /// brought them in. The first one is the root file of the program.
+    stack: Vec<(PathBuf, Option<Loc<'nsa>>)>,
+    /// The files that were already parsed completely and don't have to be included again.
+    done: HashSet<PathBuf>,
+}
+
+impl<'nsa> Includes<'nsa> {
+    fn canonical(file_path: &Path) -> PathBuf {
+        fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf())
+    }
+}
+
+/// The keywords that start the top level constructs of a program. Parsing resumes at them
+/// after an error.
+const TOP_LEVEL_KEYWORDS: &[&str] = &["include", "let", "case", "for", "halt", "run", "trace", "test"];
+
+impl<'nsa> Program<'nsa> {
+    /// Parses the whole program reporting all the errors into the diagnostics of the lexer.
+    /// Fails if there was at least one error.
+    pub fn parse(lexer: &mut Lexer<'nsa>) -> Result<Self> {
+        let errors = lexer.diagnostics.errors();
+        let program = Program::parse_recovering(lexer);
+        if lexer.diagnostics.errors() > errors {
+            return Err(())
+        }
+        Ok(program)
+    }
+
+    /// Like [`Program::parse`], but returns whatever was parsed successfully even if there
+    /// were errors. Useful for the tools that work with the programs that are being edited.
+    pub fn parse_recovering(lexer: &mut Lexer<'nsa>) -> Self {
+        let mut program = Program::default();
+        let mut includes = Includes {
+            stack: vec![(Includes::canonical(Path::new(lexer.loc().file_path)), None)],
+            done: HashSet::new(),
+        };
+        program.parse_file(lexer, &mut includes);
+        program
+    }
+
+    /// Parses `include '<path>'`. The path is resolved relative to the directory of the
+    /// including file. Each file is included only once, so several files may share the same
+    /// definitions.
+    fn parse_include(&mut self, lexer: &mut Lexer<'nsa>, includes: &mut Includes<'nsa>) -> Result<()> {
+        let keyword = lexer.expect_symbols(&["include"])?;
+        let path = lexer.parse_symbol()?;
+        let atom = Atom::from_symbol(path)?;
+        let relative_path = lexer.diagnostics.report_err(atom.expect_string())?;
+        let file_path = resolve_path(&keyword.loc, relative_path);
+        let canonical_path = Includes::canonical(&file_path);
+        if let Some(i) = includes.stack.iter().position(|(path, _)| *path == canonical_path) {
+            let mut diagnostic = Diagnostic::error(Code::Include, *atom.loc(), format!("cyclic include of file {file_path}", file_path = file_path.display()));
+            for (_, loc) in includes.stack[i + 1..].iter().rev() {
+                if let Some(loc) = loc {
+                    diagnostic = diagnostic.note(*loc, "included from here");
+                }
+            }
+            lexer.diagnostics.report(diagnostic);
+            return Err(())
+        }
+        if includes.stack.len() == 1 {
+            self.items.push(Item::Include{keyword, path});
+        }
+        if includes.done.contains(&canonical_path) {
+            return Ok(())
+        }
+        let (source, file_path) = lexer.diagnostics.report_err(read_leaked(atom.loc(), &file_path))?;
+        includes.stack.push((canonical_path, Some(*atom.loc())));
+        lexer.nested(source, file_path, |lexer| self.parse_file(lexer, includes));
+        let (canonical_path, _) = includes.stack.pop().expect("the included file was pushed above");
+        includes.done.insert(canonical_path);
+        Ok(())
+    }
+
+    /// Parses the top level constructs of the file one by one. A construct that fails to parse
+    /// is skipped up to the beginning of the next one, so all the errors of the file get reported.
+    fn parse_file(&mut self, lexer: &mut Lexer<'nsa>, includes: &mut Includes<'nsa>) {
+        while let Some(key) = lexer.peek_symbol() {
+            if self.parse_top_level(lexer, includes, key).is_err() {
+                lexer.recover(key.loc, TOP_LEVEL_KEYWORDS);
+            }
+        }
+    }
+
+    fn parse_top_level(&mut self, lexer: &mut Lexer<'nsa>, includes: &mut Includes<'nsa>, key: Symbol<'nsa>) -> Result<()> {
+        let mut item = None;
+        match key.name {
+            "include" => self.parse_include(lexer, includes)?,
+            "run" | "trace" => {
+                self.runs.push(Run::parse(lexer)?);
+                item = Some(Item::Run(self.runs.len() - 1));
+            }
+            "test" => {
+                self.tests.push(Test::parse(lexer)?);
+                item = Some(Item::Test(self.tests.len() - 1));
+            }
+            "case" | "for" | "halt" => {
+                self.statements.push(Statement::parse(lexer, &self.sets)?);
+                item = Some(Item::Statement(self.statements.len() - 1));
+            }
+            "let" => {
+                let keyword = lexer.parse_symbol()?;
+                let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+                let name = match atom {
+                    Atom::Symbol(name) => name,
+                    Atom::Integer{..} | Atom::Real{..} | Atom::String{..} => {
+                        lexer.diagnostics.report(Diagnostic::error(Code::TypeMismatch, *atom.loc(), format!("set name may not be {human}", human = atom.human())));
+                        return Err(())
+                    }
+                };
+                if MAGICAL_SETS.contains(&name.name) {
+                    lexer.diagnostics.report(Diagnostic::error(Code::Redefinition, name.loc, format!("redefinition of a magical set {name}")));
+                    return Err(());
+                }
+                if let Some((orig_name, _)) = self.sets.get_key_value(&name) {
+                    lexer.diagnostics.report(Diagnostic::error(Code::Redefinition, name.loc, format!("redefinition of set {name}"))
+                        .note(orig_name.loc, "first definition located here"));
+                    return Err(())
+                }
+                let set = SetExpr::parse(lexer, &self.sets)?;
+                self.sets.insert(name, set);
+                item = Some(Item::Let{keyword, name});
+            }
+            _ => {
+                lexer.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, key.loc, format!("unknown keyword {name}", name = key.name)));
+                return Err(())
+            }
+        }
+        if let Some(item) = item.filter(|_| includes.stack.len() == 1) {
+            self.items.push(item);
+        }
+        Ok(())
+    }
+
+    pub fn parse_source(source: &'nsa str, file_path: &'nsa str) -> Result<Self> {
+        Self::parse(&mut Lexer::new(source, file_path))
+    }
+
+    /// Reports the problems of all the statements, not just the first one.
+    pub fn sanity_check(&self, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> {
+        let mut result = Ok(());
+        for statement in &self.statements {
+            if statement.sanity_check(&self.sets, diagnostics).is_err() {
+                result = Err(())
+            }
+        }
+        result
+    }
+
+    /// Finds all the pairs of cases that may match the same State and Read.
+    /// See [`overlap::find_overlaps`].
+    pub fn overlaps(&self) -> Vec<(Case<'nsa>, Case<'nsa>)> {
+        overlap::find_overlaps(&self.sets, &self.statements)
+    }
+
+    /// Reports each pair of [`Program::overlaps`] as an error if `deny` or as a warning
+    /// otherwise.
+    pub fn report_overlaps(&self, deny: bool, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> {
+        let pairs = self.overlaps();
+        for (first, second) in &pairs {
+            let message = "case overlaps with another case on State and Read";
+            let diagnostic = if deny {
+                Diagnostic::error(Code::Overlap, second.keyword.loc, message)
+            } else {
+                Diagnostic::warning(Code::Overlap, second.keyword.loc, message)
+            };
+            diagnostics.report(diagnostic.note(first.keyword.loc, "the other case is located here and takes precedence"));
+        }
+        if deny && !pairs.is_empty() {
+            return Err(())
+        }
+        Ok(())
+    }
+
+    /// Reports each of [`Program::dead_ends`] as a warning if the program declares its halt
+    /// states.
+    pub fn report_dead_ends(&self, diagnostics: &mut Diagnostics<'nsa>) {
+        if !self.declares_halts() {
+            return
+        }
+        for case in self.dead_ends() {
+            diagnostics.report(Diagnostic::warning(Code::DeadEnd, *case.next.loc(), format!("state {next} has no cases and is not declared as a halt state", next = case.next)));
+        }
+    }
+
+    /// Finds all the cases that may switch the Machine to a State that has no cases and is not
+    /// declared as halting. See [`overlap::find_dead_ends`].
+    pub fn dead_ends(&self) -> Vec<Case<'nsa>> {
+        overlap::find_dead_ends(&self.sets, &self.statements)
+    }
+
+    /// Whether the program explicitly declares its halt states with `halt` statements.
+    pub fn declares_halts(&self) -> bool {
+        let mut result = false;
+        let mut scope = Scope::new();
+        for statement in &self.statements {
+            statement.visit_halts(&mut scope, &mut |_, _| result = true);
+        }
+        result
+    }
+
+    /// Builds the index of the cases for running the program with [`Machine::next`].
+    /// Expects the program to be already sanity checked.
+    pub fn dispatch(&self) -> Dispatch<'nsa> {
+        Dispatch::new(&self.sets, &self.statements)
+    }
+
+    /// Expands all the statements of the program into plain cases and `halt` declarations.
+    /// Fails if any of the quantified sets can't be expanded (like `Integer`).
+    pub fn expand(&self) -> Fallible<'nsa, Vec<Statement<'nsa>>> {
+        let mut result = vec![];
+        for statement in &self.statements {
+            result.extend(statement.expand(&self.sets)?);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,509 @@
+//! Language Server of the Tula programs speaking LSP over the standard input and output.
+//!
+//! The documents are synchronized in full and analyzed from scratch on every request. The
+//! programs are small enough for that.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use super::{Result, Program, Statement, Scope, Quantifier, pattern_vars};
+use super::lexer::{Lexer, Loc, Symbol};
+use super::expr::{Expr, Atom};
+use super::set_expr::MAGICAL_SETS;
+use super::diagnostics::{Diagnostics, Severity};
+use super::overlap::may_be_equal;
+use super::json::Value;
+
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INVALID_PARAMS: f64 = -32602.0;
+
+const COMPLETION_KIND_CLASS: usize = 7;
+const COMPLETION_KIND_ENUM_MEMBER: usize = 20;
+
+struct Document {
+    uri: String,
+    path: String,
+    text: String,
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&x, tail)) = rest.split_first() {
+        if let (b'%', Some(code)) = (x, tail.get(..2).and_then(|code| std::str::from_utf8(code).ok()).and_then(|code| u8::from_str_radix(code, 16).ok())) {
+            bytes.push(code);
+            rest = &tail[2..];
+        } else {
+            bytes.push(x);
+            rest = tail;
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for x in path.bytes() {
+        if x.is_ascii_alphanumeric() || b"/-_.~".contains(&x) {
+            uri.push(x as char);
+        } else {
+            uri.push_str(&format!("%{x:02X}"));
+        }
+    }
+    uri
+}
+
+/// LSP measures the columns in UTF-16 code units, [`Loc`] measures them in characters.
+fn utf16_col(line: &str, col: usize) -> usize {
+    line.chars().take(col.saturating_sub(1)).map(char::len_utf16).sum()
+}
+
+fn char_col(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    let mut col = 1;
+    for x in line.chars() {
+        if units >= character {
+            break
+        }
+        units += x.len_utf16();
+        col += 1;
+    }
+    col
+}
+
+fn position(line: usize, character: usize) -> Value {
+    Value::object([("line", line.into()), ("character", character.into())])
+}
+
+/// The range of the symbol that starts at the location.
+fn range(diagnostics: &Diagnostics, loc: &Loc) -> Value {
+    let line = diagnostics.line(loc).unwrap_or("");
+    let start = utf16_col(line, loc.col);
+    let end = utf16_col(line, loc.col + diagnostics.span_length(loc)).max(start + 1);
+    Value::object([
+        ("start", position(loc.row - 1, start)),
+        ("end", position(loc.row - 1, end)),
+    ])
+}
+
+/// A single pass of analysis of a document.
+struct Analysis<'nsa> {
+    document: &'nsa Document,
+    program: Program<'nsa>,
+    diagnostics: Diagnostics<'nsa>,
+    symbols: Vec<Symbol<'nsa>>,
+}
+
+/// An expression that denotes a State of the Machine.
+struct State<'a, 'nsa> {
+    expr: &'a Expr<'nsa>,
+    scope: Scope<'nsa>,
+    /// The Next of a case or the entry State of a run as opposed to the patterns that are
+    /// matched against the State.
+    output: bool,
+}
+
+/// Calls `f` for the statement and all the statements nested in it together with the scope of
+/// the Universal Quantifiers they are nested in. Unlike [`Statement::visit_cases`] it does not
+/// expect the statement to be sanity checked.
+fn walk<'a, 'nsa, F>(statement: &'a Statement<'nsa>, scope: &mut Scope<'nsa>, f: &mut F) where F: FnMut(&'a Statement<'nsa>, &Scope<'nsa>) {
+    f(statement, scope);
+    match statement {
+        Statement::Case(_) | Statement::Halt{..} => {}
+        Statement::Block{statements, ..} => {
+            for statement in statements {
+                walk(statement, scope, f);
+            }
+        }
+        Statement::For{pattern, set, body, ..} => {
+            let mut vars = vec![];
+            pattern_vars(pattern, &mut vars);
+            let mut shadowed = vec![];
+            for var in &vars {
+                shadowed.push(scope.insert(*var, Quantifier{pattern: pattern.clone(), set: set.clone()}));
+            }
+            walk(body, scope, f);
+            for (var, quantifier) in vars.iter().zip(shadowed) {
+                match quantifier {
+                    Some(quantifier) => scope.insert(*var, quantifier),
+                    None => scope.remove(var),
+                };
+            }
+        }
+    }
+}
+
+fn find_atom<'a, 'nsa>(expr: &'a Expr<'nsa>, loc: &Loc<'nsa>) -> Option<&'a Atom<'nsa>> {
+    match expr {
+        Expr::Atom(atom) => (atom.loc() == loc).then_some(atom),
+        Expr::Tuple{elements, ..} => elements.iter().find_map(|element| find_atom(element, loc)),
+        Expr::Eval{lhs, op, rhs, ..} => find_atom(lhs, loc).or_else(|| find_atom(op, loc)).or_else(|| find_atom(rhs, loc)),
+    }
+}
+
+impl<'nsa> Analysis<'nsa> {
+    fn new(document: &'nsa Document) -> Self {
+        let mut lexer = Lexer::new(&document.text, &document.path);
+        lexer.diagnostics.silent = true;
+        let program = Program::parse_recovering(&mut lexer);
+        let mut diagnostics = lexer.diagnostics;
+        if diagnostics.errors() == 0 && program.sanity_check(&mut diagnostics).is_ok() {
+            let _ = program.report_overlaps(false, &mut diagnostics);
+            program.report_dead_ends(&mut diagnostics);
+        }
+        let symbols = Lexer::new(&document.text, &document.path).collect();
+        Self {document, program, diagnostics, symbols}
+    }
+
+    fn location(&self, loc: &Loc) -> Value {
+        let uri = if loc.file_path == self.document.path {
+            self.document.uri.clone()
+        } else {
+            path_to_uri(loc.file_path)
+        };
+        Value::object([("uri", uri.into()), ("range", range(&self.diagnostics, loc))])
+    }
+
+    fn symbol_at(&self, line: usize, character: usize) -> Option<Symbol<'nsa>> {
+        let text = self.document.text.lines().nth(line)?;
+        let col = char_col(text, character);
+        let end = |symbol: &Symbol<'nsa>| symbol.loc.col + symbol.name.chars().count();
+        let on_line = || self.symbols.iter().copied().filter(|symbol| symbol.loc.row == line + 1 && symbol.loc.col <= col);
+        // The cursor right after the symbol still refers to it unless another symbol starts there
+        on_line().find(|symbol| col < end(symbol)).or_else(|| on_line().find(|symbol| col == end(symbol)))
+    }
+
+    /// Calls `f` for each statement of the program together with its scope.
+    fn walk<'a, F>(&'a self, f: &mut F) where F: FnMut(&'a Statement<'nsa>, &Scope<'nsa>) {
+        let mut scope = Scope::new();
+        for statement in &self.program.statements {
+            walk(statement, &mut scope, f);
+        }
+    }
+
+    fn states(&self) -> Vec<State<'_, 'nsa>> {
+        let mut states = vec![];
+        self.walk(&mut |statement, scope| match statement {
+            Statement::Case(case) => {
+                states.push(State{expr: &case.state, scope: scope.clone(), output: false});
+                states.push(State{expr: &case.next, scope: scope.clone(), output: true});
+            }
+            Statement::Halt{state, ..} => states.push(State{expr: state, scope: scope.clone(), output: false}),
+            Statement::Block{..} | Statement::For{..} => {}
+        });
+        for run in &self.program.runs {
+            states.push(State{expr: &run.state, scope: Scope::new(), output: true});
+        }
+        states
+    }
+
+    fn state_at(&self, loc: &Loc<'nsa>) -> Option<State<'_, 'nsa>> {
+        self.states().into_iter().find(|state| find_atom(state.expr, loc).is_some())
+    }
+
+    /// The Universal Quantifier of the variable at the location.
+    fn quantifier_at(&self, symbol: &Symbol<'nsa>) -> Option<Quantifier<'nsa>> {
+        let mut result = None;
+        self.walk(&mut |statement, scope| {
+            if result.is_some() {
+                return
+            }
+            let exprs = match statement {
+                Statement::Case(case) => vec![&case.state, &case.read, &case.write, &case.step, &case.next],
+                Statement::Halt{state, ..} => vec![state],
+                Statement::For{pattern, set, ..} => {
+                    if find_atom(pattern, &symbol.loc).is_some() {
+                        result = Some(Quantifier{pattern: pattern.clone(), set: set.clone()});
+                    }
+                    return
+                }
+                Statement::Block{..} => return,
+            };
+            if exprs.iter().any(|expr| find_atom(expr, &symbol.loc).is_some()) {
+                result = scope.get(symbol).cloned();
+            }
+        });
+        result
+    }
+
+    fn diagnostics(&self) -> Value {
+        let mut result = vec![];
+        for diagnostic in &self.diagnostics.reported {
+            let Some(loc) = diagnostic.loc.filter(|loc| loc.file_path == self.document.path) else {
+                continue
+            };
+            let severity: usize = match diagnostic.severity {
+                Severity::Error => 1,
+                Severity::Warning => 2,
+            };
+            let related = diagnostic.notes.iter().map(|(loc, message)| Value::object([
+                ("location", self.location(loc)),
+                ("message", message.as_str().into()),
+            ])).collect();
+            result.push(Value::object([
+                ("range", range(&self.diagnostics, &loc)),
+                ("severity", severity.into()),
+                ("code", diagnostic.code.id().into()),
+                ("source", "tula".into()),
+                ("message", diagnostic.message.as_str().into()),
+                ("relatedInformation", Value::Array(related)),
+            ]));
+        }
+        Value::Array(result)
+    }
+
+    fn hover(&self, symbol: &Symbol<'nsa>) -> Value {
+        let Some(Quantifier{pattern, set}) = self.quantifier_at(symbol) else {
+            return Value::Null
+        };
+        Value::object([
+            ("contents", Value::object([
+                ("kind", "markdown".into()),
+                ("value", format!("```tula\nfor {pattern} in {set}\n```").into()),
+            ])),
+            ("range", range(&self.diagnostics, &symbol.loc)),
+        ])
+    }
+
+    /// The cases and the `halt` declarations the Next of a case or the entry State of a run
+    /// may switch the Machine to, or the definition of a named set.
+    fn definition(&self, symbol: &Symbol<'nsa>) -> Value {
+        if let Some(state) = self.state_at(&symbol.loc) {
+            if !state.output {
+                return Value::Null
+            }
+            let targets = self.states().into_iter()
+                .filter(|target| !target.output && may_be_equal(&self.program.sets, state.expr, &state.scope, target.expr, &target.scope))
+                .map(|target| self.location(target.expr.loc()))
+                .collect();
+            return Value::Array(targets)
+        }
+        match self.program.sets.get_key_value(symbol) {
+            Some((name, _)) => Value::Array(vec![self.location(&name.loc)]),
+            None => Value::Null,
+        }
+    }
+
+    /// All the places the State at the location may be mentioned at: the States and the Nexts
+    /// of the cases, the `halt` declarations and the entry States of the runs.
+    fn references(&self, symbol: &Symbol<'nsa>) -> Value {
+        let Some(state) = self.state_at(&symbol.loc) else {
+            return Value::Null
+        };
+        let references = self.states().into_iter()
+            .filter(|other| may_be_equal(&self.program.sets, state.expr, &state.scope, other.expr, &other.scope))
+            .map(|other| self.location(other.expr.loc()))
+            .collect();
+        Value::Array(references)
+    }
+
+    /// The names of the sets and the States that are matched by the cases and `halt`
+    /// declarations. The client filters them by the prefix.
+    fn completion(&self) -> Value {
+        let mut items = vec![];
+        let mut add = |label: &str, kind: usize, detail: &str| {
+            if !items.iter().any(|(other, _, _)| *other == label) {
+                items.push((label.to_string(), kind, detail.to_string()));
+            }
+        };
+        for name in MAGICAL_SETS {
+            add(name, COMPLETION_KIND_CLASS, "magical set");
+        }
+        let mut names: Vec<_> = self.program.sets.iter().collect();
+        names.sort_by_key(|(name, _)| (name.loc.file_path, name.loc.row, name.loc.col));
+        for (name, set) in names {
+            add(name.name, COMPLETION_KIND_CLASS, &format!("let {name} {set}"));
+        }
+        for state in self.states() {
+            if state.output {
+                continue
+            }
+            let head = match state.expr {
+                Expr::Tuple{elements, ..} => elements.first(),
+                expr => Some(expr),
+            };
+            if let Some(Expr::Atom(Atom::Symbol(symbol))) = head {
+                if !state.scope.contains_key(symbol) {
+                    add(symbol.name, COMPLETION_KIND_ENUM_MEMBER, "state");
+                }
+            }
+        }
+        Value::Array(items.into_iter().map(|(label, kind, detail)| Value::object([
+            ("label", label.into()),
+            ("kind", kind.into()),
+            ("detail", detail.into()),
+        ])).collect())
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None)
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length header"))
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    // Malformed messages are ignored, the client is going to time out on them.
+    Ok(Some(std::str::from_utf8(&body).ok().and_then(Value::parse).unwrap_or(Value::Null)))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {length}\r\n\r\n{body}", length = body.len())?;
+    output.flush()
+}
+
+struct Server<W> {
+    output: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn respond(&mut self, id: &Value, result: Value) -> io::Result<()> {
+        write_message(&mut self.output, &Value::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.clone()),
+            ("result", result),
+        ]))
+    }
+
+    fn respond_error(&mut self, id: &Value, code: f64, message: &str) -> io::Result<()> {
+        write_message(&mut self.output, &Value::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.clone()),
+            ("error", Value::object([("code", Value::Number(code)), ("message", message.into())])),
+        ]))
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        write_message(&mut self.output, &Value::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(document) => Analysis::new(document).diagnostics(),
+            None => Value::Array(vec![]),
+        };
+        self.notify("textDocument/publishDiagnostics", Value::object([
+            ("uri", uri.into()),
+            ("diagnostics", diagnostics),
+        ]))
+    }
+
+    fn open(&mut self, uri: &str, text: &str) -> io::Result<()> {
+        self.documents.insert(uri.to_string(), Document {
+            uri: uri.to_string(),
+            path: uri_to_path(uri),
+            text: text.to_string(),
+        });
+        self.publish_diagnostics(uri)
+    }
+
+    /// Handles the requests about the symbol at the position in the document.
+    fn at_position(&mut self, id: &Value, method: &str, params: &Value) -> io::Result<()> {
+        let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(Value::as_str);
+        let position = params.get("position");
+        let line = position.and_then(|position| position.get("line")).and_then(Value::as_usize);
+        let character = position.and_then(|position| position.get("character")).and_then(Value::as_usize);
+        let (Some(document), Some(line), Some(character)) = (uri.and_then(|uri| self.documents.get(uri)), line, character) else {
+            return self.respond_error(id, INVALID_PARAMS, "unknown document or position")
+        };
+        let analysis = Analysis::new(document);
+        let result = match (method, analysis.symbol_at(line, character)) {
+            ("textDocument/completion", _) => analysis.completion(),
+            (_, None) => Value::Null,
+            ("textDocument/hover", Some(symbol)) => analysis.hover(&symbol),
+            ("textDocument/definition", Some(symbol)) => analysis.definition(&symbol),
+            ("textDocument/references", Some(symbol)) => analysis.references(&symbol),
+            _ => unreachable!("only the methods about positions are handled here"),
+        };
+        self.respond(id, result)
+    }
+
+    /// Returns `false` when it's time to exit.
+    fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        let id = message.get("id");
+        let method = message.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Value::Null);
+        match (method, id) {
+            ("initialize", Some(id)) => {
+                let capabilities = Value::object([
+                    ("textDocumentSync", 1.into()),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("completionProvider", Value::object([])),
+                ]);
+                self.respond(id, Value::object([
+                    ("capabilities", capabilities),
+                    ("serverInfo", Value::object([("name", "tula".into())])),
+                ]))?;
+            }
+            ("shutdown", Some(id)) => self.respond(id, Value::Null)?,
+            ("exit", _) => return Ok(false),
+            ("textDocument/didOpen", None) => {
+                let document = params.get("textDocument");
+                let uri = document.and_then(|document| document.get("uri")).and_then(Value::as_str);
+                let text = document.and_then(|document| document.get("text")).and_then(Value::as_str);
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.open(uri, text)?;
+                }
+            }
+            ("textDocument/didChange", None) => {
+                let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(Value::as_str);
+                let text = match params.get("contentChanges") {
+                    Some(Value::Array(changes)) => changes.last().and_then(|change| change.get("text")).and_then(Value::as_str),
+                    _ => None,
+                };
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.open(uri, text)?;
+                }
+            }
+            ("textDocument/didClose", None) => {
+                if let Some(uri) = params.get("textDocument").and_then(|document| document.get("uri")).and_then(Value::as_str) {
+                    self.documents.remove(uri);
+                    self.publish_diagnostics(uri)?;
+                }
+            }
+            ("textDocument/hover" | "textDocument/definition" | "textDocument/references" | "textDocument/completion", Some(id)) => {
+                self.at_position(id, method, params)?;
+            }
+            (_, Some(id)) => self.respond_error(id, METHOD_NOT_FOUND, &format!("unsupported method {method}"))?,
+            (_, None) => {}
+        }
+        Ok(true)
+    }
+}
+
+/// Serves the client until it asks to exit or closes the input.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> Result<()> {
+    let mut server = Server{output, documents: HashMap::new()};
+    let result = (|| {
+        while let Some(message) = read_message(&mut input)? {
+            if !server.handle(&message)? {
+                break
+            }
+        }
+        Ok(())
+    })();
+    result.map_err(|err: io::Error| {
+        eprintln!("ERROR: language server failed: {err}");
+    })
+}
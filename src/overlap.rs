@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use num_bigint::BigInt;
+use super::lexer::{Symbol, Loc};
+use super::expr::{Expr, Atom};
+use super::set_expr::{SetExpr, Sets};
+use super::{Scope, Quantifier, Case, Statement};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Var<'nsa> {
+    /// A variable of one of the two compared cases.
+    Case(usize, Symbol<'nsa>),
+    /// A variable introduced in place of an Eval node or while destructuring a Cartesian Product.
+    Fresh(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Term<'nsa> {
+    Var(Var<'nsa>),
+    Atom(Atom<'nsa>),
+    Tuple(Vec<Term<'nsa>>),
+}
+
+impl<'nsa> Term<'nsa> {
+    /// Patterns never contain Eval nodes, the sanity check forbids them.
+    fn from_pattern(pattern: &Expr<'nsa>, side: usize, scope: &Scope<'nsa>) -> Self {
+        Term::from_expr(pattern, side, scope, &mut None).expect("Eval node is found in a pattern. Sanity check was not performed before the analysis.")
+    }
+
+    /// Unlike patterns the outputs of the cases may contain Eval nodes. The result of their
+    /// evaluation is unknown upfront so they become fresh unconstrained variables.
+    fn from_output(output: &Expr<'nsa>, side: usize, scope: &Scope<'nsa>, fresh: &mut usize) -> Self {
+        let mut counter = Some(*fresh);
+        let term = Term::from_expr(output, side, scope, &mut counter).expect("Eval nodes are always replaced with fresh variables");
+        *fresh = counter.expect("The counter is never taken away");
+        term
+    }
+
+    fn from_expr(expr: &Expr<'nsa>, side: usize, scope: &Scope<'nsa>, fresh: &mut Option<usize>) -> Option<Self> {
+        match expr {
+            Expr::Atom(Atom::Symbol(symbol)) if scope.contains_key(symbol) => Some(Term::Var(Var::Case(side, *symbol))),
+            Expr::Atom(atom) => Some(Term::Atom(atom.clone())),
+            Expr::Tuple{elements, ..} => {
+                let mut terms = vec![];
+                for element in elements {
+                    terms.push(Term::from_expr(element, side, scope, fresh)?);
+                }
+                Some(Term::Tuple(terms))
+            }
+            Expr::Eval{..} => {
+                let fresh = fresh.as_mut()?;
+                *fresh += 1;
+                Some(Term::Var(Var::Fresh(*fresh)))
+            }
+        }
+    }
+
+    fn from_value(value: &Expr<'nsa>) -> Self {
+        match value {
+            Expr::Atom(atom) => Term::Atom(atom.clone()),
+            Expr::Tuple{elements, ..} => Term::Tuple(elements.iter().map(Term::from_value).collect()),
+            Expr::Eval{..} => unreachable!("Set elements are always forced"),
+        }
+    }
+
+    /// Converts a term without variables back into a value.
+    fn to_value(&self, loc: Loc<'nsa>) -> Option<Expr<'nsa>> {
+        match self {
+            Term::Var(_) => None,
+            Term::Atom(atom) => Some(Expr::Atom(atom.clone())),
+            Term::Tuple(terms) => {
+                let mut elements = vec![];
+                for term in terms {
+                    elements.push(term.to_value(loc)?);
+                }
+                Some(Expr::Tuple{loc, elements})
+            }
+        }
+    }
+}
+
+type Subst<'nsa> = HashMap<Var<'nsa>, Term<'nsa>>;
+
+fn resolve<'nsa>(term: &Term<'nsa>, subst: &Subst<'nsa>) -> Term<'nsa> {
+    match term {
+        Term::Var(var) => match subst.get(var) {
+            Some(bound) => resolve(bound, subst),
+            None => term.clone(),
+        }
+        Term::Atom(_) => term.clone(),
+        Term::Tuple(terms) => Term::Tuple(terms.iter().map(|term| resolve(term, subst)).collect()),
+    }
+}
+
+fn occurs<'nsa>(var: &Var<'nsa>, term: &Term<'nsa>) -> bool {
+    match term {
+        Term::Var(other) => var == other,
+        Term::Atom(_) => false,
+        Term::Tuple(terms) => terms.iter().any(|term| occurs(var, term)),
+    }
+}
+
+fn unify<'nsa>(a: &Term<'nsa>, b: &Term<'nsa>, subst: &mut Subst<'nsa>) -> bool {
+    match (resolve(a, subst), resolve(b, subst)) {
+        (Term::Var(a), Term::Var(b)) if a == b => true,
+        (Term::Var(var), term) | (term, Term::Var(var)) => {
+            if occurs(&var, &term) {
+                return false
+            }
+            subst.insert(var, term);
+            true
+        }
+        (Term::Atom(a), Term::Atom(b)) => a == b,
+        (Term::Tuple(a), Term::Tuple(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| unify(a, b, subst))
+        }
+        _ => false,
+    }
+}
+
+/// Searches for a value that satisfies all the `pending` constraints "term is an element of the
+/// set". Finite sets are enumerated, Cartesian Products are destructured and the magical sets
+/// are checked at the very end when the terms are either known or are simple variables.
+///
+/// The search is conservative: whenever it can't prove that the constraints are unsatisfiable
+/// (for instance a Difference with an infinite set applied to a variable) it assumes they are
+/// satisfiable.
+struct Solver<'a, 'nsa> {
+    sets: &'a Sets<'nsa>,
+    loc: Loc<'nsa>,
+    fresh: usize,
+}
+
+impl<'a, 'nsa> Solver<'a, 'nsa> {
+    fn solve(&mut self, mut pending: Vec<(Term<'nsa>, SetExpr<'nsa>)>, deferred: Vec<(Term<'nsa>, SetExpr<'nsa>, bool)>, subst: &Subst<'nsa>) -> bool {
+        let Some((term, set)) = pending.pop() else {
+            return self.finish(&deferred, subst)
+        };
+        let term = resolve(&term, subst);
+        if let Some(value) = term.to_value(self.loc) {
+            return set.contains(self.sets, &value) && self.solve(pending, deferred, subst)
+        }
+        match set {
+            SetExpr::Named(name) => {
+                let set = self.sets.get(&name).expect("The existence of all Named Set Expressions must be checked upfront").clone();
+                pending.push((term, set));
+                self.solve(pending, deferred, subst)
+            }
+            SetExpr::Enclosed{inner, ..} => {
+                pending.push((term, *inner));
+                self.solve(pending, deferred, subst)
+            }
+            SetExpr::Union{lhs, rhs} => {
+                let mut other = pending.clone();
+                pending.push((term.clone(), *lhs));
+                if self.solve(pending, deferred.clone(), subst) {
+                    return true
+                }
+                other.push((term, *rhs));
+                self.solve(other, deferred, subst)
+            }
+            SetExpr::Diff{lhs, rhs} => {
+                let mut deferred = deferred;
+                deferred.push((term.clone(), *rhs, false));
+                pending.push((term, *lhs));
+                self.solve(pending, deferred, subst)
+            }
+            SetExpr::Product{elements} => {
+                let mut subst = subst.clone();
+                let terms = match term {
+                    Term::Tuple(terms) => terms,
+                    Term::Var(var) => {
+                        let terms: Vec<_> = elements.iter().map(|_| {
+                            self.fresh += 1;
+                            Term::Var(Var::Fresh(self.fresh))
+                        }).collect();
+                        subst.insert(var, Term::Tuple(terms.clone()));
+                        terms
+                    }
+                    Term::Atom(_) => return false,
+                };
+                if terms.len() != elements.len() {
+                    return false
+                }
+                pending.extend(terms.into_iter().zip(elements));
+                self.solve(pending, deferred, &subst)
+            }
+            SetExpr::Anonymous{elements, ..} => self.solve_any(&term, elements.iter(), pending, deferred, subst),
+            SetExpr::Boolean(_) => {
+                let elements = set.expand(self.sets).expect("Boolean is finite");
+                self.solve_any(&term, elements.iter(), pending, deferred, subst)
+            }
+            SetExpr::Integer(_) | SetExpr::Real(_) | SetExpr::String(_) | SetExpr::Char(_) | SetExpr::Byte(_) | SetExpr::Range{..} => {
+                let mut deferred = deferred;
+                deferred.push((term, set, true));
+                self.solve(pending, deferred, subst)
+            }
+        }
+    }
+
+    /// Tries each element of a finite set as the value of the term.
+    fn solve_any<'b>(&mut self, term: &Term<'nsa>, elements: impl Iterator<Item = &'b Expr<'nsa>>, pending: Vec<(Term<'nsa>, SetExpr<'nsa>)>, deferred: Vec<(Term<'nsa>, SetExpr<'nsa>, bool)>, subst: &Subst<'nsa>) -> bool where 'nsa: 'b {
+        for element in elements {
+            let mut subst = subst.clone();
+            if unify(term, &Term::from_value(element), &mut subst) && self.solve(pending.clone(), deferred.clone(), &subst) {
+                return true
+            }
+        }
+        false
+    }
+
+    fn finish(&self, deferred: &[(Term<'nsa>, SetExpr<'nsa>, bool)], subst: &Subst<'nsa>) -> bool {
+        let mut domains: HashMap<Var<'nsa>, Domain> = HashMap::new();
+        for (term, set, positive) in deferred {
+            let term = resolve(term, subst);
+            if let Some(value) = term.to_value(self.loc) {
+                if set.contains(self.sets, &value) != *positive {
+                    return false
+                }
+            } else if *positive {
+                match term {
+                    Term::Var(var) => {
+                        let domain = match domains.remove(&var) {
+                            Some(domain) => domain.intersect(Domain::of(set)),
+                            None => Some(Domain::of(set)),
+                        };
+                        match domain {
+                            Some(domain) if !domain.is_empty() => {
+                                domains.insert(var, domain);
+                            }
+                            _ => return false,
+                        }
+                    }
+                    Term::Tuple(_) => return false,
+                    Term::Atom(_) => unreachable!(),
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The values a variable may take according to the deferred magical sets it belongs to.
+struct Domain {
+    /// The kind of the atoms of the sets. Sets of different kinds are disjoint, while sets of the
+    /// same kind (like `String` and `Char`) are assumed to intersect unless their bounds say otherwise.
+    kind: &'static str,
+    /// The bounds of the Integer sets.
+    start: Option<BigInt>,
+    end: Option<BigInt>,
+}
+
+impl Domain {
+    fn of(set: &SetExpr) -> Self {
+        let (kind, start, end) = match set {
+            SetExpr::Integer(_) => ("Integer", None, None),
+            SetExpr::Byte(_) => ("Integer", Some(BigInt::from(0)), Some(BigInt::from(256))),
+            SetExpr::Range{start, end, ..} => ("Integer", Some(start.clone()), Some(end.clone())),
+            SetExpr::Real(_) => ("Real", None, None),
+            SetExpr::String(_) | SetExpr::Char(_) => ("String", None, None),
+            _ => unreachable!("only the magical sets that are not enumerated are deferred"),
+        };
+        Domain{kind, start, end}
+    }
+
+    fn intersect(self, other: Domain) -> Option<Domain> {
+        if self.kind != other.kind {
+            return None
+        }
+        let start = match (self.start, other.start) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        let end = match (self.end, other.end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Some(Domain{kind: self.kind, start, end})
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!((&self.start, &self.end), (Some(start), Some(end)) if start >= end)
+    }
+}
+
+/// A flattened case with the scope of its Universal Quantifiers.
+struct ScopedCase<'nsa> {
+    case: Case<'nsa>,
+    scope: Scope<'nsa>,
+}
+
+fn scoped_cases<'nsa>(statements: &[Statement<'nsa>]) -> Vec<ScopedCase<'nsa>> {
+    let mut cases = vec![];
+    let mut scope = Scope::new();
+    for statement in statements {
+        statement.visit_cases(&mut scope, &mut |case, scope| cases.push(ScopedCase {
+            case: case.clone(),
+            scope: scope.clone(),
+        }));
+    }
+    cases
+}
+
+/// Checks whether there are values of the variables of both scopes (taken from the sets of their
+/// quantifiers) that make each pair of terms equal.
+fn unifiable<'nsa>(sets: &Sets<'nsa>, pairs: &[(Term<'nsa>, Term<'nsa>)], scopes: [&Scope<'nsa>; 2], loc: Loc<'nsa>, fresh: usize) -> bool {
+    let mut subst = Subst::new();
+    for (a, b) in pairs {
+        if !unify(a, b, &mut subst) {
+            return false
+        }
+    }
+    let mut pending = vec![];
+    for (side, scope) in scopes.iter().enumerate() {
+        for Quantifier{pattern, set} in scope.values() {
+            pending.push((Term::from_pattern(pattern, side, scope), set.clone()));
+        }
+    }
+    let mut solver = Solver {sets, loc, fresh};
+    solver.solve(pending, vec![], &subst)
+}
+
+fn overlap<'nsa>(sets: &Sets<'nsa>, a: &ScopedCase<'nsa>, b: &ScopedCase<'nsa>) -> bool {
+    let a_state = Term::from_pattern(&a.case.state, 0, &a.scope);
+    let a_read = Term::from_pattern(&a.case.read, 0, &a.scope);
+    let b_state = Term::from_pattern(&b.case.state, 1, &b.scope);
+    let b_read = Term::from_pattern(&b.case.read, 1, &b.scope);
+    unifiable(sets, &[(a_state, b_state), (a_read, b_read)], [&a.scope, &b.scope], a.case.keyword.loc, 0)
+}
+
+/// Finds all the pairs of cases that may match the same State and Read. The first case of
+/// each pair precedes the second one in the source order and takes precedence at runtime.
+///
+/// Expects the statements to be already sanity checked.
+pub fn find_overlaps<'nsa>(sets: &Sets<'nsa>, statements: &[Statement<'nsa>]) -> Vec<(Case<'nsa>, Case<'nsa>)> {
+    let cases = scoped_cases(statements);
+    let mut overlaps = vec![];
+    for (i, a) in cases.iter().enumerate() {
+        for b in &cases[i + 1..] {
+            if overlap(sets, a, b) {
+                overlaps.push((a.case.clone(), b.case.clone()));
+            }
+        }
+    }
+    overlaps
+}
+
+/// Checks whether the values of the expressions (like the Next of one case and the State of
+/// another one) may be equal for some values of the variables of their scopes. Eval nodes may
+/// evaluate to anything.
+pub fn may_be_equal<'nsa>(sets: &Sets<'nsa>, a: &Expr<'nsa>, a_scope: &Scope<'nsa>, b: &Expr<'nsa>, b_scope: &Scope<'nsa>) -> bool {
+    let mut fresh = 0;
+    let a_term = Term::from_output(a, 0, a_scope, &mut fresh);
+    let b_term = Term::from_output(b, 1, b_scope, &mut fresh);
+    unifiable(sets, &[(a_term, b_term)], [a_scope, b_scope], *a.loc(), fresh)
+}
+
+/// Finds all the cases that may switch the Machine to a State that has no cases and is not
+/// declared by any of the `halt` statements.
+///
+/// Expects the statements to be already sanity checked.
+pub fn find_dead_ends<'nsa>(sets: &Sets<'nsa>, statements: &[Statement<'nsa>]) -> Vec<Case<'nsa>> {
+    let cases = scoped_cases(statements);
+    let mut halts = vec![];
+    let mut scope = Scope::new();
+    for statement in statements {
+        statement.visit_halts(&mut scope, &mut |state, scope| halts.push((state.clone(), scope.clone())));
+    }
+
+    let mut dead_ends = vec![];
+    for a in &cases {
+        let mut fresh = 0;
+        let next = Term::from_output(&a.case.next, 0, &a.scope, &mut fresh);
+        let continues = cases.iter().map(|b| (&b.case.state, &b.scope))
+            .chain(halts.iter().map(|(state, scope)| (state, scope)))
+            .any(|(state, scope)| unifiable(sets, &[(next.clone(), Term::from_pattern(state, 1, scope))], [&a.scope, scope], a.case.keyword.loc, fresh));
+        if !continues {
+            dead_ends.push(a.case.clone());
+        }
+    }
+    dead_ends
+}
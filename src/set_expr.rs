@@ -3,9 +3,17 @@ use std::fmt;
 use super::lexer::{Lexer, Symbol, Loc};
 use super::expr::{Expr, Atom};
 use super::Result;
+use super::diagnostics::{Code, Diagnostic, Fallible};
+use num_bigint::BigInt;
 
 pub type Sets<'nsa> = HashMap<Symbol<'nsa>, SetExpr<'nsa>>;
 
+/// Names of the sets that are built into the language and may not be redefined.
+pub const MAGICAL_SETS: &[&str] = &["Integer", "Real", "String", "Boolean", "Char", "Byte"];
+
+/// The ranges with more elements are not expanded, just like `Integer`.
+pub const MAX_RANGE_LEN: u32 = 1 << 16;
+
 #[derive(Debug, Clone)]
 pub enum SetExpr<'nsa> {
     Named(Symbol<'nsa>),
@@ -16,13 +24,32 @@ pub enum SetExpr<'nsa> {
         loc: Loc<'nsa>,
         inner: Box<SetExpr<'nsa>>
     },
+    /// Elements are kept in the source order.
     Anonymous {
         loc: Loc<'nsa>,
-        elements: HashSet<Expr<'nsa>>
+        elements: Vec<Expr<'nsa>>,
+        /// Same elements for the membership tests, so they don't scan the whole `elements`.
+        index: HashSet<Expr<'nsa>>,
     },
     Integer(Symbol<'nsa>),
     Real(Symbol<'nsa>),
     String(Symbol<'nsa>),
+    /// The symbols `true` and `false` produced by the comparison and logical operations.
+    Boolean(Symbol<'nsa>),
+    /// Strings that consist of exactly one Unicode scalar value.
+    Char(Symbol<'nsa>),
+    /// Integers from 0 to 255 inclusive.
+    Byte(Symbol<'nsa>),
+    /// Integers from `start` inclusive to `end` exclusive.
+    ///
+    /// Written as `start..end` or `start..=end` if the `end` is inclusive, in which case the
+    /// `end` is one past the written one.
+    Range {
+        loc: Loc<'nsa>,
+        start: BigInt,
+        end: BigInt,
+        inclusive: bool,
+    },
     Union {
         lhs: Box<SetExpr<'nsa>>,
         rhs: Box<SetExpr<'nsa>>,
@@ -43,6 +70,11 @@ impl<'nsa> fmt::Display for SetExpr<'nsa> {
             Self::Integer(_) => write!(f, "Integer"),
             Self::Real(_) => write!(f, "Real"),
             Self::String(_) => write!(f, "String"),
+            Self::Boolean(_) => write!(f, "Boolean"),
+            Self::Char(_) => write!(f, "Char"),
+            Self::Byte(_) => write!(f, "Byte"),
+            Self::Range{start, end, inclusive: false, ..} => write!(f, "{start}..{end}"),
+            Self::Range{start, end, inclusive: true, ..} => write!(f, "{start}..={last}", last = end - 1),
             Self::Enclosed{inner, ..} => write!(f, "({inner})"),
             Self::Product {elements, ..} => {
                 for (i, element) in elements.iter().enumerate() {
@@ -78,42 +110,50 @@ impl<'nsa> SetExpr<'nsa> {
             Self::Integer(Symbol{loc, ..}) => loc,
             Self::Real(Symbol{loc, ..}) => loc,
             Self::String(Symbol{loc, ..}) => loc,
+            Self::Boolean(Symbol{loc, ..}) => loc,
+            Self::Char(Symbol{loc, ..}) => loc,
+            Self::Byte(Symbol{loc, ..}) => loc,
+            Self::Range{loc, ..} => loc,
             Self::Union {lhs, ..} => lhs.loc(),
             Self::Diff {lhs, ..} => lhs.loc(),
             Self::Product {elements} => elements.first().expect("Parser must not produce products that have 0 elements").loc(),
         }
     }
 
-    fn parse_anonymous(lexer: &mut Lexer<'nsa>) -> Result<HashSet<Expr<'nsa>>> {
+    fn parse_anonymous(lexer: &mut Lexer<'nsa>) -> Result<(Vec<Expr<'nsa>>, HashSet<Expr<'nsa>>)> {
         let _ = lexer.expect_symbols(&["{"])?;
-        let mut set: HashSet<Expr<'nsa>> = HashSet::new();
+        let mut set: Vec<Expr<'nsa>> = vec![];
+        let mut index: HashSet<Expr<'nsa>> = HashSet::new();
         while let Some(symbol) = lexer.peek_symbol() {
             if symbol.name == "}" {
                 break;
             }
-            let value = Expr::parse(lexer)?.force_evals()?;
-            if let Some(existing_value) = set.get(&value) {
-                eprintln!("{loc}: ERROR: Set may only consist of non-repeating values", loc = value.loc());
-                eprintln!("{loc}: NOTE: Same value was provided here", loc = existing_value.loc());
+            let value = Expr::parse(lexer)?;
+            let value = lexer.diagnostics.report_err(value.force_evals())?;
+            if let Some(existing_value) = index.get(&value) {
+                lexer.diagnostics.report(Diagnostic::error(Code::RepeatingValues, *value.loc(), "Set may only consist of non-repeating values")
+                    .note(*existing_value.loc(), "Same value was provided here"));
                 return Err(());
             }
-            set.insert(value);
+            index.insert(value.clone());
+            set.push(value);
         }
         let _ = lexer.expect_symbols(&["}"])?;
-        Ok(set)
+        Ok((set, index))
     }
 
     fn parse_primary(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
         let Some(symbol) = lexer.peek_symbol() else {
-            eprintln!("{loc}: ERROR: expected symbol but reached the end of the input", loc = lexer.loc());
+            lexer.diagnostics.report(Diagnostic::error(Code::UnexpectedSymbol, lexer.loc(), "expected symbol but reached the end of the input"));
             return Err(())
         };
         let set = match symbol.name {
             "{" => {
-                let elements = Self::parse_anonymous(lexer)?;
+                let (elements, index) = Self::parse_anonymous(lexer)?;
                 Self::Anonymous {
                     loc: symbol.loc,
-                    elements
+                    elements,
+                    index,
                 }
             },
             "(" => {
@@ -127,16 +167,21 @@ impl<'nsa> SetExpr<'nsa> {
                 let atom = Atom::from_symbol(symbol)?;
                 match atom {
                     Atom::Integer{..} | Atom::Real{..} | Atom::String{..} => {
-                        eprintln!("{loc}: ERROR: {human} is not a set expression", loc = atom.loc(), human = atom.human());
+                        lexer.diagnostics.report(Diagnostic::error(Code::TypeMismatch, *atom.loc(), format!("{human} is not a set expression", human = atom.human())));
                         return Err(())
                     }
                     Atom::Symbol(symbol) => match symbol.name {
                         "Integer" => Self::Integer(symbol),
                         "Real" => Self::Real(symbol),
                         "String" => Self::String(symbol),
-                        _ => {
+                        "Boolean" => Self::Boolean(symbol),
+                        "Char" => Self::Char(symbol),
+                        "Byte" => Self::Byte(symbol),
+                        _ => if let Some(range) = Self::parse_range(&symbol) {
+                            range
+                        } else {
                             if !sets.contains_key(&symbol) {
-                                eprintln!("{loc}: ERROR: set {symbol} does not exist", loc = symbol.loc);
+                                lexer.diagnostics.report(Diagnostic::error(Code::UnknownSet, symbol.loc, format!("set {symbol} does not exist")));
                                 return Err(());
                             }
                             Self::Named(symbol)
@@ -148,6 +193,21 @@ impl<'nsa> SetExpr<'nsa> {
         Ok(set)
     }
 
+    /// Parses `start..end` or `start..=end` where both bounds are Integers.
+    fn parse_range(symbol: &Symbol<'nsa>) -> Option<Self> {
+        let (start, end) = symbol.name.split_once("..")?;
+        let (end, inclusive) = match end.strip_prefix('=') {
+            Some(end) => (end, true),
+            None => (end, false),
+        };
+        let start = start.parse::<BigInt>().ok()?;
+        let mut end = end.parse::<BigInt>().ok()?;
+        if inclusive {
+            end += 1;
+        }
+        Some(Self::Range{loc: symbol.loc, start, end, inclusive})
+    }
+
     fn parse_product(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
         let mut elements = vec![Self::parse_primary(lexer, sets)?];
         while let Some(symbol) = lexer.peek_symbol() {
@@ -215,10 +275,23 @@ impl<'nsa> SetExpr<'nsa> {
             }
             Self::Union{lhs, rhs} => lhs.contains(sets, element) || rhs.contains(sets, element),
             Self::Diff{lhs, rhs} => lhs.contains(sets, element) && !rhs.contains(sets, element),
-            Self::Anonymous{elements, ..} => elements.contains(element),
+            Self::Anonymous{index, ..} => index.contains(element),
             Self::Integer(_) => matches!(element, Expr::Atom(Atom::Integer{..})),
             Self::Real(_) => matches!(element, Expr::Atom(Atom::Real{..})),
             Self::String(_) => matches!(element, Expr::Atom(Atom::String{..})),
+            Self::Boolean(_) => matches!(element, Expr::Atom(Atom::Symbol(Symbol{name: "true" | "false", ..}))),
+            Self::Char(_) => match element {
+                Expr::Atom(Atom::String{value, ..}) => value.chars().count() == 1,
+                _ => false,
+            }
+            Self::Byte(_) => match element {
+                Expr::Atom(Atom::Integer{value, ..}) => u8::try_from(value).is_ok(),
+                _ => false,
+            }
+            Self::Range{start, end, ..} => match element {
+                Expr::Atom(Atom::Integer{value, ..}) => start <= value && value < end,
+                _ => false,
+            }
             Self::Named(name) => {
                 sets.get(name)
                     .expect("The existence of all Named Set Expressions must be checked upfront")
@@ -227,7 +300,13 @@ impl<'nsa> SetExpr<'nsa> {
         }
     }
 
-    pub fn expand(&self, sets: &Sets<'nsa>) -> Result<HashSet<Expr<'nsa>>> {
+    /// Lists all the elements of the set in a stable order:
+    /// - Anonymous sets keep the source order of their elements;
+    /// - Ranges and Bytes go in the ascending order, Booleans go as `true` then `false`;
+    /// - Unions list the elements of the left hand side followed by the new elements of the
+    ///   right hand side, Differences keep the order of the left hand side;
+    /// - Cartesian Products go in the lexicographic order of their components.
+    pub fn expand(&self, sets: &Sets<'nsa>) -> Fallible<'nsa, Vec<Expr<'nsa>>> {
         match self {
             Self::Product{elements} => {
                 let mut product = vec![];
@@ -235,25 +314,50 @@ impl<'nsa> SetExpr<'nsa> {
                     product.push(element.expand(sets)?)
                 }
                 let mut elements = vec![];
-                let mut result = HashSet::new();
+                let mut result = vec![];
                 expand_product_recursively(&product, self.loc(), &mut elements, &mut result);
                 Ok(result)
             }
             Self::Enclosed{inner, ..} => inner.expand(sets),
-            Self::Union{lhs, rhs} => Ok(lhs.expand(sets)?.union(&rhs.expand(sets)?).cloned().collect()),
-            Self::Diff{lhs, rhs} => Ok(lhs.expand(sets)?.difference(&rhs.expand(sets)?).cloned().collect()),
+            Self::Union{lhs, rhs} => {
+                let mut result = lhs.expand(sets)?;
+                let seen: HashSet<_> = result.iter().cloned().collect();
+                result.extend(rhs.expand(sets)?.into_iter().filter(|element| !seen.contains(element)));
+                Ok(result)
+            }
+            Self::Diff{lhs, rhs} => {
+                Ok(lhs.expand(sets)?.into_iter().filter(|element| !rhs.contains(sets, element)).collect())
+            }
             Self::Anonymous{elements, ..} => Ok(elements.clone()),
             Self::Integer(Symbol{loc, ..})=> {
-                eprintln!("{loc}: Impossible to expand set Integer: it's too big");
-                Err(())
+                Err(Diagnostic::error(Code::InfiniteSet, *loc, "impossible to expand set Integer: it's too big"))
             }
             Self::Real(Symbol{loc, ..})=> {
-                eprintln!("{loc}: Impossible to expand set Real: it's too big");
-                Err(())
+                Err(Diagnostic::error(Code::InfiniteSet, *loc, "impossible to expand set Real: it's too big"))
             }
             Self::String(Symbol{loc, ..})=> {
-                eprintln!("{loc}: Impossible to expand set String: it's too big");
-                Err(())
+                Err(Diagnostic::error(Code::InfiniteSet, *loc, "impossible to expand set String: it's too big"))
+            }
+            Self::Boolean(Symbol{loc, ..}) => {
+                Ok(["true", "false"].into_iter().map(|name| Expr::Atom(Atom::Symbol(Symbol{name, loc: *loc}))).collect())
+            }
+            Self::Char(Symbol{loc, ..})=> {
+                Err(Diagnostic::error(Code::InfiniteSet, *loc, "impossible to expand set Char: it's too big"))
+            }
+            Self::Byte(Symbol{loc, ..}) => {
+                Ok((0..=u8::MAX).map(|value| Expr::Atom(Atom::Integer{loc: *loc, value: value.into()})).collect())
+            }
+            Self::Range{loc, start, end, ..} => {
+                if end - start > BigInt::from(MAX_RANGE_LEN) {
+                    return Err(Diagnostic::error(Code::InfiniteSet, *loc, format!("impossible to expand set {self}: it's bigger than {MAX_RANGE_LEN} elements")))
+                }
+                let mut result = vec![];
+                let mut value = start.clone();
+                while value < *end {
+                    result.push(Expr::Atom(Atom::Integer{loc: *loc, value: value.clone()}));
+                    value += 1;
+                }
+                Ok(result)
             }
             Self::Named(name) => {
                 sets.get(name)
@@ -264,7 +368,7 @@ impl<'nsa> SetExpr<'nsa> {
     }
 }
 
-fn expand_product_recursively<'nsa>(product: &[HashSet<Expr<'nsa>>], element_loc: &Loc<'nsa>, elements: &mut Vec<Expr<'nsa>>, result: &mut HashSet<Expr<'nsa>>) {
+fn expand_product_recursively<'nsa>(product: &[Vec<Expr<'nsa>>], element_loc: &Loc<'nsa>, elements: &mut Vec<Expr<'nsa>>, result: &mut Vec<Expr<'nsa>>) {
     match product {
         [head, tail @ ..] => {
             for element in head {
@@ -274,8 +378,7 @@ fn expand_product_recursively<'nsa>(product: &[HashSet<Expr<'nsa>>], element_loc
             }
         }
         [] => {
-            let new = result.insert(Expr::Tuple{elements: elements.clone(), loc: *element_loc});
-            assert!(new);
+            result.push(Expr::Tuple{elements: elements.clone(), loc: *element_loc});
         }
     }
 }
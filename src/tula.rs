@@ -1,660 +1,700 @@
-#[macro_use]
-mod lexer;
-mod expr;
-mod set_expr;
-
 use std::fs;
-use std::result;
-use std::fmt::{self, Write};
+use std::fmt::Write as _;
+use std::io::{self, Write, IsTerminal};
 use std::env;
 use std::process::ExitCode;
+use std::time::{Duration, Instant};
 use std::collections::{HashMap};
-use std::ops::{Index, IndexMut};
-use unicode_width::UnicodeWidthStr;
-
-use lexer::*;
-use expr::*;
-use set_expr::*;
-
-type Result<T> = result::Result<T, ()>;
-
-#[derive(Debug, Clone)]
-struct Case<'nsa> {
-    keyword: Symbol<'nsa>,
-    state: Expr<'nsa>,
-    read: Expr<'nsa>,
-    write: Expr<'nsa>,
-    step: Expr<'nsa>,
-    next: Expr<'nsa>,
-}
-
-impl<'nsa> Case<'nsa> {
-    fn parse(lexer: &mut Lexer<'nsa>, keyword: Symbol<'nsa>) -> Result<Self> {
-        let state = Expr::parse(lexer)?;
-        let read  = Expr::parse(lexer)?;
-        let write = Expr::parse(lexer)?;
-        let step  = Expr::parse(lexer)?;
-        let next  = Expr::parse(lexer)?;
-        Ok(Case{keyword, state, read, write, step, next})
-    }
-
-    fn substitute_bindings(&self, bindings: &HashMap<Symbol<'nsa>, Expr<'nsa>>) -> Self {
-        let Case{keyword, state, read, write, step, next} = self;
-        let state = state.substitute_bindings(bindings);
-        let read  = read.substitute_bindings(bindings);
-        let write = write.substitute_bindings(bindings);
-        let step  = step.substitute_bindings(bindings);
-        let next  = next.substitute_bindings(bindings);
-        let keyword = *keyword;
-        Case{keyword, state, read, write, step, next}
-    }
-}
 
-#[derive(Debug, Clone)]
-enum Statement<'nsa> {
-    Case(Case<'nsa>),
-    Block {
-        statements: Vec<Statement<'nsa>>
-    },
-    For {
-        var: Symbol<'nsa>,
-        set: SetExpr<'nsa>,
-        body: Box<Statement<'nsa>>,
-    }
-}
-
-impl<'nsa> fmt::Display for Statement<'nsa> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Block{statements} => {
-                write!(f, "{{")?;
-                for (i, statement) in statements.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, " ")?;
-                    }
-                    write!(f, "{statement}")?;
-                }
-                write!(f, "}}")
-            }
-            Self::Case(Case{keyword, state, read, write, step, next}) => {
-                write!(f, "{keyword} {state} {read} {write} {step} {next}")
-            }
-            Self::For{var, set, body} => {
-                write!(f, "for {var} in {set} {body}")
-            }
-        }
-    }
-}
-
-type Scope<'nsa> = HashMap<Symbol<'nsa>, SetExpr<'nsa>>;
-
-impl<'nsa> Statement<'nsa> {
-    fn parse(lexer: &mut Lexer<'nsa>, sets: &Sets<'nsa>) -> Result<Self> {
-        let key = lexer.expect_symbols(&["case", "for", "{"])?;
-        match key.name {
-            "case" => Ok(Statement::Case(Case::parse(lexer, key)?)),
-            "{" => {
-                let mut statements = vec![];
-                while let Some(symbol) = lexer.peek_symbol() {
-                    if symbol.name == "}" {
-                        break;
-                    }
-                    statements.push(Statement::parse(lexer, sets)?);
-                }
-                let _ = lexer.expect_symbols(&["}"])?;
-                Ok(Statement::Block{statements})
-            }
-            "for" => {
-                let mut vars = vec![];
-                while let Some(symbol) = lexer.peek_symbol() {
-                    if symbol.name == "in" {
-                        break;
-                    }
-                    let expr = Expr::parse(lexer)?;
-                    match expr {
-                        Expr::Atom(atom) => {
-                            match atom {
-                                Atom::Symbol(symbol) => vars.push(symbol),
-                                Atom::Integer{..} | Atom::Real{..} | Atom::String{..} => {
-                                    eprintln!("{loc}: ERROR: {human} may not be used as variable names", loc = atom.loc(), human = atom.human());
-                                    return Err(())
-                                }
-                            }
-                        }
-                        Expr::Eval{loc, ..} | Expr::Tuple{loc, ..} => {
-                            eprintln!("{loc}: ERROR: Pattern Matching in Universal Quantifiers is not supported");
-                            return Err(())
-                        }
-                    }
-                }
-                let _ = lexer.expect_symbols(&["in"])?;
-                let set = SetExpr::parse(lexer, sets)?;
-                let mut result = Statement::parse(lexer, sets)?;
-                for var in vars.iter().rev() {
-                    result = Statement::For{
-                        var: *var,
-                        set: set.clone(),
-                        body: Box::new(result)
-                    }
-                }
-                Ok(result)
+use tula::{Result, Program, Statement, Machine, Transition, RunKind, Run, Case, Action, Tape, Test};
+use tula::json;
+use tula::diagnostics::{Code, Diagnostic, Diagnostics, MessageFormat};
+use unicode_width::UnicodeWidthStr;
+use tula::debugger::{Debugger, Breakpoint, Stop};
+use tula::graph::Graph;
+use tula::lexer::*;
+use tula::expr::*;
+
+fn print_expanded<'nsa>(statement: &Statement<'nsa>, cache: &mut HashMap<Expr<'nsa>, usize>, enumerate: bool) {
+    match statement {
+        Statement::Case(case) => {
+            let Case{keyword, state, read, write, step, next} = &**case;
+            if enumerate {
+                let state = state.enumerate(cache);
+                let read = read.enumerate(cache);
+                let write = write.enumerate(cache);
+                let next = next.enumerate(cache);
+                println!("{keyword} {state} {read} {write} {step} {next}");
+            } else {
+                println!("{keyword} {state} {read} {write} {step} {next}");
             }
-            _ => unreachable!()
         }
-    }
-
-    fn match_next_case_scoped(&self, scope: &mut Scope<'nsa>, sets: &Sets<'nsa>, state: &Expr<'nsa>, read: &Expr<'nsa>) -> Result<Option<(Expr<'nsa>, Expr<'nsa>, Expr<'nsa>)>> {
-        match self {
-            Statement::Case(case) => {
-                let mut bindings = HashMap::new();
-
-                if !case.state.clone().force_evals()?.pattern_match(state, scope, &mut bindings) {
-                    return Ok(None)
-                }
-                if !case.read.clone().force_evals()?.pattern_match(read, scope, &mut bindings) {
-                    return Ok(None)
-                }
-
-                for (var, set) in scope.iter() {
-                    if let Some(value) = bindings.get(var) {
-                        if !set.contains(sets, value) {
-                            return Ok(None)
-                        }
-                    } else {
-                        unreachable!("Unused variable is found at runtime. Sanity check was not performed before execution.");
-                    }
-                }
-
-                Ok(Some((
-                    case.write.substitute_bindings(&bindings).force_evals()?,
-                    case.step.substitute_bindings(&bindings).force_evals()?,
-                    case.next.substitute_bindings(&bindings).force_evals()?
-                )))
-            }
-            Statement::Block{statements} => {
-                for statement in statements {
-                    if let Some(result) = statement.match_next_case_scoped(scope, sets, state, read)? {
-                        return Ok(Some(result))
-                    }
-                }
-                Ok(None)
-            }
-            Statement::For{var, set, body} => {
-                let shadowed = scope.insert(*var, set.clone()).is_some();
-                assert!(!shadowed, "A variable is shadowed at runtime. Sanity check was not performed before execution.");
-                let result = body.match_next_case_scoped(scope, sets, state, read)?;
-                scope.remove(var);
-                Ok(result)
+        Statement::Halt{keyword, state} => {
+            if enumerate {
+                println!("{keyword} {state}", state = state.enumerate(cache));
+            } else {
+                println!("{keyword} {state}");
             }
         }
+        Statement::Block{..} | Statement::For{..} => unreachable!("Expanded statements never contain blocks or quantifiers"),
     }
+}
 
-    fn match_next_case(&self, sets: &Sets<'nsa>, state: &Expr<'nsa>, read: &Expr<'nsa>) -> Result<Option<(Expr<'nsa>, Expr<'nsa>, Expr<'nsa>)>>{
-        let mut scope = Scope::new();
-        self.match_next_case_scoped(&mut scope, sets, state, read)
+fn print_run<'nsa>(run: &Run<'nsa>, cache: &mut HashMap<Expr<'nsa>, usize>, enumerate: bool) {
+    print!("{kind}", kind = run.kind);
+    if enumerate {
+        print!(" {entry}", entry = run.state.enumerate(cache));
+    } else {
+        print!(" {entry}", entry = run.state);
     }
-
-    fn expand_bound(&self, bindings: &mut HashMap<Symbol<'nsa>, Expr<'nsa>>, cache: &mut HashMap<Expr<'nsa>, usize>, sets: &Sets<'nsa>, enumerate: bool) -> Result<()> {
-        match self {
-            Statement::Case(case) => {
-                let Case{keyword, state, read, write, step, next} = case.substitute_bindings(bindings).clone();
-                let write = write.clone().force_evals()?;
-                let step = step.clone().force_evals()?;
-                let next = next.clone().force_evals()?;
-                if enumerate {
-                    let state = state.enumerate(cache);
-                    let read = read.enumerate(cache);
-                    let write = write.enumerate(cache);
-                    let next = next.enumerate(cache);
-                    println!("{keyword} {state} {read} {write} {step} {next}");
-                } else {
-                    println!("{keyword} {state} {read} {write} {step} {next}");
-                }
-            }
-            Statement::For{var, set, body} => {
-                for element in set.expand(sets)?.iter() {
-                    let shadowed = bindings.insert(*var, element.clone()).is_some();
-                    assert!(!shadowed, "A variable is shadowed at expansion. Sanity check was not performed before execution.");
-                    body.expand_bound(bindings, cache, sets, enumerate)?;
-                    bindings.remove(var);
-                }
-            }
-            Statement::Block{statements} => {
-                for statement in statements {
-                    statement.expand_bound(bindings, cache, sets, enumerate)?;
-                }
-            }
+    print!(" {{");
+    for (i, expr) in run.tape.left.iter().chain(run.tape.right.iter()).enumerate() {
+        if i > 0 {
+            print!(" ");
         }
-        Ok(())
-    }
-
-    fn expand(&self, sets: &Sets<'nsa>, cache: &mut HashMap<Expr<'nsa>, usize>, enumerate: bool) -> Result<()> {
-        let mut bindings = HashMap::new();
-        self.expand_bound(&mut bindings, cache, sets, enumerate)
-    }
-
-    fn sanity_check_scoped(&self, scope: &mut Scope<'nsa>) -> Result<()> {
-        match self {
-            Statement::Case(case) => {
-                let mut unused_vars = vec![];
-                for (var, _) in scope.iter() {
-                    if case.state.uses_var(var).or_else(|| case.read.uses_var(var)).is_none() {
-                        unused_vars.push(var);
-                    }
-                }
-                if !unused_vars.is_empty() {
-                    eprintln!("{loc}: ERROR: not all variables in the scope are used in the input of the case", loc = case.keyword.loc);
-                    unused_vars.sort();
-                    for var in unused_vars {
-                        eprintln!("{loc}: NOTE: unused variable {var}", loc = var.loc);
-                    }
-                    return Err(())
-                }
-            }
-            Statement::Block{statements} => {
-                for statement in statements {
-                    statement.sanity_check_scoped(scope)?
-                }
-            }
-            Statement::For{var, set, body} => {
-                if let Some((shadowed_var, _)) = scope.get_key_value(var) {
-                    println!("{loc}: ERROR: {var} shadows another name in the higher scope", loc = var.loc);
-                    println!("{loc}: NOTE: the shadowed name is located here", loc = shadowed_var.loc);
-                    return Err(())
-                }
-                scope.insert(*var, set.clone());
-                body.sanity_check_scoped(scope)?;
-                scope.remove(var);
-            }
+        if enumerate {
+            print!("{expr}", expr = expr.enumerate(cache));
+        } else {
+            print!("{expr}");
         }
-        Ok(())
     }
+    println!("}}");
+}
 
-    fn sanity_check(&self) -> Result<()> {
-        let mut scope = Scope::new();
-        self.sanity_check_scoped(&mut scope)
+fn program_usage(program_name: &str) {
+    eprintln!("Usage: {program_name} <command> [ARGUMENTS]");
+    eprintln!("Commands:");
+    for command in COMMANDS.iter() {
+        let command_name = &command.name;
+        let command_signature = &command.signature;
+        let command_description = &command.description;
+        eprintln!("    {command_name} {command_signature} - {command_description}");
     }
 }
 
-#[derive(Debug, Clone)]
-struct Tape<'nsa> {
-    left: Vec<Expr<'nsa>>,
-    left_default: Expr<'nsa>,
-    right: Vec<Expr<'nsa>>,
-    right_default: Expr<'nsa>,
+fn command_usage(program_name: &str, command: &Command) {
+    let command_name = &command.name;
+    let command_signature = &command.signature;
+    eprintln!("Usage: {program_name} {command_name} {command_signature}");
 }
 
-impl<'nsa> Tape<'nsa> {
-    fn new(left: Vec<Expr<'nsa>>, right: Vec<Expr<'nsa>>) -> Option<Self> {
-        let left_default = left.first().or(right.first())?.clone();
-        let right_default = right.last().or(left.last())?.clone();
-        Some(Self {
-            left, left_default,
-            right, right_default,
-        })
+/// Reports the problem of the command line. The usage of the command is only printed for
+/// humans, so the JSON stream is not interrupted.
+fn usage_error(program_name: &str, command: &Command, message: impl Into<String>, diagnostics: &mut Diagnostics) {
+    if diagnostics.format == MessageFormat::Text {
+        command_usage(program_name, command);
     }
+    diagnostics.report(Diagnostic::global(Code::CommandLine, message));
+}
 
-    fn touch(&mut self, index: i32) {
-        if index >= 0 {
-            let index = index as usize;
-            while index >= self.right.len() {
-                self.right.push(self.right_default.clone());
-            }
-        } else {
-            let index = (index.abs() - 1) as usize;
-            while index >= self.left.len() {
-                self.left.push(self.left_default.clone());
-            }
-        }
-    }
+fn set_message_format(command: &Command, program_name: &str, name: &str, diagnostics: &mut Diagnostics) -> Result<()> {
+    diagnostics.format = MessageFormat::from_name(name).ok_or_else(|| {
+        usage_error(program_name, command, format!("unknown message format {name}"), diagnostics);
+    })?;
+    Ok(())
 }
 
-impl<'nsa> Index<i32> for Tape<'nsa> {
-    type Output = Expr<'nsa>;
-    fn index(&self, index: i32) -> &Expr<'nsa> {
-        if index >= 0 {
-            let index = index as usize;
-            if index >= self.right.len() {
-                &self.right_default
-            } else {
-                &self.right[index]
-            }
-        } else {
-            let index = (index.abs() - 1) as usize;
-            if index >= self.left.len() {
-                &self.left_default
-            } else {
-                &self.left[index]
-            }
-        }
-    }
+#[derive(Clone, Copy, PartialEq)]
+enum Overlaps {
+    Allow,
+    Warn,
+    Deny,
 }
 
-impl<'nsa> IndexMut<i32> for Tape<'nsa> {
-    fn index_mut(&mut self, index: i32) -> &mut Expr<'nsa> {
-        if index >= 0 {
-            let index = index as usize;
-            &mut self.right[index]
-        } else {
-            let index = (index.abs() - 1) as usize;
-            &mut self.left[index]
-        }
+fn check_overlaps<'nsa>(program: &Program<'nsa>, overlaps: Overlaps, diagnostics: &mut Diagnostics<'nsa>) -> Result<()> {
+    if overlaps == Overlaps::Allow {
+        return Ok(())
     }
+    program.report_overlaps(overlaps == Overlaps::Deny, diagnostics)
 }
 
-#[derive(Debug)]
-struct Machine<'nsa> {
-    state: Expr<'nsa>,
-    tape: Tape<'nsa>,
-    head: i32,
-    halt: bool,
+#[derive(Clone, Copy, PartialEq)]
+enum TraceFormat {
+    Text,
+    /// A single JSON array of step objects.
+    Json,
+    /// One step object per line.
+    JsonLines,
 }
 
-impl<'nsa> Machine<'nsa> {
-    fn next(&mut self, statements: &[Statement<'nsa>], sets: &Sets<'nsa>) -> Result<()> {
-        for statement in statements {
-            if let Some((write, step, next)) = statement.match_next_case(sets, &self.state, &self.tape[self.head])? {
-                self.tape[self.head] = write.force_evals()?;
-                let step = step.expect_atom()?.expect_symbol()?;
-                match step.name {
-                    "<-" => {
-                        self.head -= 1;
-                        self.tape.touch(self.head);
-                    }
-                    "->" => {
-                        self.head += 1;
-                        self.tape.touch(self.head);
-                    }
-                    "." => {}
-                    "!" => self.print(),
-                    _ => {
-                        eprintln!("{loc}: ERROR: unknown step action {step}", loc = step.loc);
-                        return Err(())
-                    }
-                }
-                self.state = next;
-                self.halt = false;
-                break;
-            }
+impl TraceFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" => Some(TraceFormat::Json),
+            "jsonl" => Some(TraceFormat::JsonLines),
+            _ => None,
         }
-        Ok(())
     }
+}
 
-    fn print(&self) {
-        for expr in self.tape.left.iter().rev() {
-            print!("{expr} ");
+/// Renders the configuration of the machine as an unfinished JSON object of the trace event.
+/// The caller appends the fields specific to the event and closes the object.
+fn start_json_event(event: &str, run: &Run, machine: &Machine) -> String {
+    let mut buffer = String::new();
+    let _ = write!(&mut buffer, "{{\"event\":{event},\"run\":{run},\"step\":{step},\"state\":{state},\"head\":{head}",
+                   event = json::Str(event),
+                   run = json::Str(&run.keyword.loc.to_string()),
+                   step = machine.steps,
+                   state = json::Str(&machine.state.to_string()),
+                   head = machine.head);
+    let tape_start = machine.tape.cells().next().map(|(i, _)| i).unwrap_or(0);
+    let _ = write!(&mut buffer, ",\"tape_start\":{tape_start},\"tape\":[");
+    for (j, (_, expr)) in machine.tape.cells().enumerate() {
+        if j > 0 {
+            buffer.push(',');
         }
-        for expr in self.tape.right.iter() {
-            print!("{expr} ");
-        }
-        println!()
+        let _ = write!(&mut buffer, "{expr}", expr = json::Str(&expr.to_string()));
     }
+    buffer.push(']');
+    buffer
+}
 
-    fn trace(&self) {
-        let mut buffer = String::new();
-        let _ = write!(&mut buffer, "{state}: ", state = self.state);
-        let mut head_begin = 0;
-        let mut head_end = 0;
-        let mut iter = self.tape.left
-            .iter()
-            .enumerate()
-            .map(|(i, x)| (-(i as i32 + 1), x))
-            .rev()
-            .chain(self.tape.right.iter().enumerate().map(|(i, x)| (i as i32, x)));
-        if let Some((i, expr)) = iter.next() {
-            if i == self.head {
-                head_begin = buffer.len();
-            }
-            let _ = write!(&mut buffer, "{expr}");
-            if i == self.head {
-                head_end = buffer.len();
-            }
-        }
-        for (i, expr) in iter {
-            let _ = write!(&mut buffer, " ");
-            if i == self.head {
-                head_begin = buffer.len();
-            }
-            let _ = write!(&mut buffer, "{expr}");
-            if i == self.head {
-                head_end = buffer.len();
+/// Prints the finished trace event. `first` tracks whether the event needs a separator in
+/// front of it within the array of [`TraceFormat::Json`].
+fn print_json_event(trace_format: TraceFormat, first: &mut bool, event: &str) {
+    match trace_format {
+        TraceFormat::Json => {
+            if !*first {
+                println!(",");
             }
+            print!("{event}");
+            *first = false;
         }
-        //                     head_end
-        //                     v
-        // "State: aaa bbb cccc dddd"
-        //                 ^
-        //                 head_begin
-        println!("{buffer}");
-        print!("{pad:width$}", pad = "", width = UnicodeWidthStr::width(&buffer[0..head_begin]));
-        println!("{x:~<width$}", x = "^", width = UnicodeWidthStr::width(&buffer[head_begin..head_end]));
+        TraceFormat::JsonLines => println!("{event}"),
+        TraceFormat::Text => unreachable!("Text traces are not made of events"),
     }
 }
 
-#[derive(PartialEq)]
-enum RunKind {
-    Run,
-    Trace,
+/// The sources live until the end of the process, so the diagnostics reported about them
+/// may be collected all the way up to [`start`].
+fn read_source(file_path: &str, diagnostics: &mut Diagnostics) -> Result<&'static str> {
+    let source = fs::read_to_string(file_path).map_err(|err| {
+        diagnostics.report(Diagnostic::global(Code::CommandLine, format!("could not read file {file_path}: {err}")));
+    })?;
+    Ok(Box::leak(source.into_boxed_str()))
 }
 
-impl RunKind {
-    fn from_name(name: &str) -> Option<RunKind> {
-        match name {
-            "run" => Some(RunKind::Run),
-            "trace" => Some(RunKind::Trace),
-            _ => None
-        }
-    }
+fn parse_program(file_path: &str, diagnostics: &mut Diagnostics<'static>) -> Result<Program<'static>> {
+    parse_program_source(read_source(file_path, diagnostics)?, Box::leak(file_path.into()), diagnostics)
 }
 
-impl fmt::Display for RunKind {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            RunKind::Run => write!(f, "run"),
-            RunKind::Trace => write!(f, "trace"),
-        }
-    }
+fn parse_program_source(source: &'static str, file_path: &'static str, diagnostics: &mut Diagnostics<'static>) -> Result<Program<'static>> {
+    let mut lexer = Lexer::new(source, file_path);
+    lexer.diagnostics.format = diagnostics.format;
+    let program = Program::parse(&mut lexer);
+    diagnostics.extend(lexer.diagnostics);
+    program
 }
 
-struct Run<'nsa> {
-    kind: RunKind,
-    keyword: Symbol<'nsa>,
-    state: Expr<'nsa>,
-    tape: Tape<'nsa>,
-}
-
-impl<'nsa> Run<'nsa> {
-    fn parse(lexer: &mut Lexer<'nsa>) -> Result<Self> {
-        let keyword = lexer.expect_symbols(&["run", "trace"])?;
-        let kind = RunKind::from_name(keyword.name).unwrap();
-        let state = Expr::parse(lexer)?.force_evals()?;
-        let (open_curly_of_tape_seq, mut tape_seq) = Self::parse_tape_seq(lexer)?;
-        if let Some(symbol) = lexer.peek_symbol() {
-            if symbol.name == "{" {
-                let (_open_curly_of_tape_seq_right, tape_seq_right) = Self::parse_tape_seq(lexer)?;
-                tape_seq.reverse();
-                if let Some(tape) = Tape::new(tape_seq, tape_seq_right) {
-                    return Ok(Run {keyword, state, tape, kind})
-                } else {
-                    eprintln!("{loc}: ERROR: The tape may not be empty. It must contain at least one symbol so we know what to fill it with", loc = open_curly_of_tape_seq.loc);
-                    return Err(());
-                }
-            }
-        }
-        if let Some(tape) = Tape::new(vec![], tape_seq) {
-            Ok(Run {keyword, state, tape, kind})
+/// Renders the expected and the actual configurations one under another aligning the cells
+/// by their indices and marking the cells that differ.
+fn render_test_diff(test: &Test, machine: &Machine) -> String {
+    let expected_state = test.expected_state.to_string();
+    let actual_state = machine.state.to_string();
+    let state_width = UnicodeWidthStr::width(expected_state.as_str()).max(UnicodeWidthStr::width(actual_state.as_str()));
+    let mut expected = String::new();
+    let mut actual = String::new();
+    let mut marks = String::new();
+    let _ = write!(&mut expected, "    expected: {expected_state}:{pad:width$}", pad = "", width = state_width - UnicodeWidthStr::width(expected_state.as_str()));
+    let _ = write!(&mut actual, "    actual:   {actual_state}:{pad:width$}", pad = "", width = state_width - UnicodeWidthStr::width(actual_state.as_str()));
+    if test.expected_state == machine.state {
+        let _ = write!(&mut marks, "              {pad:state_width$} ", pad = "");
+    } else {
+        let _ = write!(&mut marks, "              {x:~<state_width$} ", x = "^");
+    }
+    for i in test.tape_range(&machine.tape) {
+        let expected_cell = test.expected_tape[i].to_string();
+        let actual_cell = machine.tape[i].to_string();
+        let width = UnicodeWidthStr::width(expected_cell.as_str()).max(UnicodeWidthStr::width(actual_cell.as_str()));
+        let _ = write!(&mut expected, " {expected_cell:width$}");
+        let _ = write!(&mut actual, " {actual_cell:width$}");
+        if test.expected_tape[i] == machine.tape[i] {
+            let _ = write!(&mut marks, " {pad:width$}", pad = "");
         } else {
-            eprintln!("{loc}: ERROR: The tape may not be empty. It must contain at least one symbol so we know what to fill it with", loc = open_curly_of_tape_seq.loc);
-            Err(())
+            let _ = write!(&mut marks, " {x:~<width$}", x = "^");
         }
     }
-
-    fn parse_tape_seq(lexer: &mut Lexer<'nsa>) -> Result<(Symbol<'nsa>, Vec<Expr<'nsa>>)> {
-        let open_curly = lexer.expect_symbols(&["{"])?;
-        let mut seq = vec![];
-        while let Some(symbol) = lexer.peek_symbol() {
-            if symbol.name == "}" {
-                break;
-            }
-            seq.push(Expr::parse(lexer)?.force_evals()?);
-        }
-        let _ = lexer.expect_symbols(&["}"])?;
-        Ok((open_curly, seq))
+    let mut diff = format!("{expected}\n{actual}", expected = expected.trim_end(), actual = actual.trim_end());
+    if !marks.trim().is_empty() {
+        diff.push('\n');
+        diff.push_str(marks.trim_end());
     }
+    diff
+}
 
-    fn expand(&self, cache: &mut HashMap<Expr<'nsa>, usize>, enumerate: bool) {
-        print!("{kind}", kind = self.kind);
-        if enumerate {
-            print!(" {entry}", entry = self.state.enumerate(cache));
+/// Runs all the tests of the file reporting each one of them. Returns the amount of the passed
+/// and the failed tests.
+fn run_tests(file_path: &str, diagnostics: &mut Diagnostics<'static>) -> Result<(usize, usize)> {
+    let program = parse_program(file_path, diagnostics)?;
+    program.sanity_check(diagnostics)?;
+    let dispatch = program.dispatch();
+    let mut passed = 0;
+    let mut failed = 0;
+    for test in &program.tests {
+        let loc = test.keyword.loc;
+        let Ok(machine) = diagnostics.report_err(test.run(&dispatch)) else {
+            println!("{loc}: FAILED: runtime error");
+            failed += 1;
+            continue;
+        };
+        if test.passed(&machine) {
+            println!("{loc}: PASSED");
+            passed += 1;
+            continue;
+        }
+        failed += 1;
+        if !machine.halt {
+            println!("{loc}: FAILED: did not halt after {steps} steps", steps = machine.steps);
         } else {
-            print!(" {entry}", entry = self.state);
+            println!("{loc}: FAILED: halted in an unexpected configuration after {steps} steps", steps = machine.steps);
         }
-        print!(" {{");
-        for (i, expr) in self.tape.left.iter().chain(self.tape.right.iter()).enumerate() {
-            if i > 0 {
-                print!(" ");
-            }
-            if enumerate {
-                print!("{expr}", expr = expr.enumerate(cache));
-            } else {
-                print!("{expr}");
+        println!("{diff}", diff = render_test_diff(test, &machine));
+        if let Some(head) = test.expected_head {
+            if head != machine.head {
+                println!("    expected the head at {head}, but it is at {actual}", actual = machine.head);
             }
         }
-        println!("}}");
     }
+    Ok((passed, failed))
 }
 
-fn parse_program<'nsa>(lexer: &mut Lexer<'nsa>) -> Result<(Sets<'nsa>, Vec<Statement<'nsa>>, Vec<Run<'nsa>>)> {
-    let mut statements = vec![];
-    let mut sets = Sets::new();
-    let mut runs = vec![];
-    while let Some(key) = lexer.peek_symbol() {
-        match key.name {
-            "run" | "trace" => {
-                runs.push(Run::parse(lexer)?);
-            }
-            "case" | "for" => {
-                statements.push(Statement::parse(lexer, &sets)?);
+/// Exit code of the `run` command when some of the runs were stopped by `--max-steps` or `--timeout`.
+/// Distinct from the generic failure so scripts can tell "did not halt" from "error".
+const EXIT_DID_NOT_HALT: u8 = 2;
+
+const DEBUGGER_COMMANDS: &[(&str, &str)] = &[
+    ("step [N]", "Perform N steps (1 by default) stopping at breakpoints"),
+    ("continue", "Run until the Machine halts or hits a breakpoint"),
+    ("back [N]", "Revert N steps (1 by default) stopping at breakpoints"),
+    ("reverse-continue", "Revert steps until the start of the run or a breakpoint"),
+    ("goto <step>", "Go to the configuration after the given amount of steps ignoring breakpoints"),
+    ("break state <pattern>", "Stop when the State matches the pattern"),
+    ("break read <pattern>", "Stop when the value under the head matches the pattern"),
+    ("break cell <index> <pattern>", "Stop when the value of the cell matches the pattern"),
+    ("break case [<file>:]<row>", "Stop before the case at the row performs a step"),
+    ("delete <N>", "Delete the breakpoint number N"),
+    ("breakpoints", "List all the breakpoints"),
+    ("tape [radius]", "Print the State and the cells around the head"),
+    ("bindings", "Print the values of the variables of the last matched case"),
+    ("help", "Print this help"),
+    ("quit", "Quit the debugger"),
+];
+
+fn print_stop(debugger: &Debugger, stop: Stop) {
+    match stop {
+        Stop::Done => {}
+        Stop::Halted => println!("Halted after {steps} steps", steps = debugger.machine.steps),
+        Stop::Start => println!("Reached the start of the run"),
+        Stop::Breakpoint(i) => println!("Hit breakpoint {i}: {breakpoint}", breakpoint = debugger.breakpoints[i]),
+    }
+    println!("{trace}", trace = debugger.machine.render_trace());
+}
+
+fn debug_command<'nsa>(debugger: &mut Debugger<'nsa>, line: &'nsa str) -> Result<bool> {
+    let mut lexer = Lexer::new(line, "<command>");
+    let Some(command) = lexer.next_symbol() else {
+        return Ok(true)
+    };
+    let mut print_tape = |machine: &Machine, transition: &Transition| {
+        if transition.action == Action::Print {
+            println!("{tape}", tape = machine.render_tape());
+        }
+    };
+    match command.name {
+        "step" | "s" => {
+            let steps = match lexer.next_symbol() {
+                Some(symbol) => lexer.diagnostics.report_err(Atom::from_symbol(symbol)?.expect_integer())?.clone(),
+                None => 1.into(),
+            };
+            let Ok(steps) = usize::try_from(steps) else {
+                lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, command.loc, "amount of steps may not be negative"));
+                return Err(())
+            };
+            let stop = lexer.diagnostics.report_err(debugger.resume(Some(steps), &mut print_tape))?;
+            print_stop(debugger, stop);
+        }
+        "continue" | "c" => {
+            let stop = lexer.diagnostics.report_err(debugger.resume(None, &mut print_tape))?;
+            print_stop(debugger, stop);
+        }
+        "back" => {
+            let steps = match lexer.next_symbol() {
+                Some(symbol) => lexer.diagnostics.report_err(Atom::from_symbol(symbol)?.expect_integer())?.clone(),
+                None => 1.into(),
+            };
+            let Ok(steps) = usize::try_from(steps) else {
+                lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, command.loc, "amount of steps may not be negative"));
+                return Err(())
+            };
+            let stop = lexer.diagnostics.report_err(debugger.resume_back(Some(steps)))?;
+            print_stop(debugger, stop);
+        }
+        "reverse-continue" | "rc" => {
+            let stop = lexer.diagnostics.report_err(debugger.resume_back(None))?;
+            print_stop(debugger, stop);
+        }
+        "goto" => {
+            let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+            let step = lexer.diagnostics.report_err(atom.expect_integer())?;
+            let Ok(step) = usize::try_from(step) else {
+                lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, command.loc, "step number may not be negative"));
+                return Err(())
+            };
+            let stop = lexer.diagnostics.report_err(debugger.goto(step, &mut print_tape))?;
+            print_stop(debugger, stop);
+        }
+        "break" | "b" => {
+            let breakpoint = Breakpoint::parse(&mut lexer, debugger.sets())?;
+            println!("Breakpoint {i}: {breakpoint}", i = debugger.breakpoints.len());
+            debugger.breakpoints.push(breakpoint);
+        }
+        "delete" | "d" => {
+            let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
+            let index = lexer.diagnostics.report_err(atom.expect_integer())?;
+            let Some(i) = usize::try_from(index).ok().filter(|i| *i < debugger.breakpoints.len()) else {
+                lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, command.loc, format!("no breakpoint number {index}")));
+                return Err(())
+            };
+            debugger.breakpoints.remove(i);
+        }
+        "breakpoints" => {
+            for (i, breakpoint) in debugger.breakpoints.iter().enumerate() {
+                println!("Breakpoint {i}: {breakpoint}");
             }
-            "let" => {
-                lexer.next_symbol();
-                let atom = Atom::from_symbol(lexer.parse_symbol()?)?;
-                let name = match atom {
-                    Atom::Symbol(name) => name,
-                    Atom::Integer{..} | Atom::Real{..} | Atom::String{..} => {
-                        eprintln!("{loc}: ERROR: set name may not be {human}", loc = atom.loc(), human = atom.human());
-                        return Err(())
-                    }
-                };
-                // TODO: improve extendability of this piece of code.
-                //   If I add more magical sets, it's easy to forget to update this match.
-                match name.name {
-                    "Integer" | "Real" | "String" => {
-                        eprintln!("{loc}: ERROR: redefinition of a magical set {name}", loc = name.loc);
-                        return Err(());
-                    }
-                    _ => {}
+        }
+        "tape" | "t" => {
+            let radius = match lexer.next_symbol() {
+                Some(symbol) => {
+                    let atom = Atom::from_symbol(symbol)?;
+                    let radius = lexer.diagnostics.report_err(atom.expect_integer())?;
+                    Some(usize::try_from(radius).map_err(|_| {
+                        lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, symbol.loc, "radius may not be negative"));
+                    })?)
                 }
-                if let Some((orig_name, _)) = sets.get_key_value(&name) {
-                    eprintln!("{loc}: ERROR: redefinition of set {name}", loc = name.loc);
-                    eprintln!("{loc}: NOTE: first definition located here", loc = orig_name.loc);
-                    return Err(())
+                None => None,
+            };
+            println!("{trace}", trace = debugger.machine.render_trace_window(radius));
+        }
+        "bindings" => {
+            if let Some(transition) = &debugger.last {
+                println!("{loc}: case", loc = transition.case);
+                let mut bindings: Vec<_> = transition.bindings.iter().collect();
+                bindings.sort_by_key(|(var, _)| var.name);
+                for (var, value) in bindings {
+                    println!("    {var} = {value}");
                 }
-                sets.insert(name, SetExpr::parse(lexer, &sets)?);
+            } else {
+                println!("No steps were performed yet");
             }
-            _ => {
-                eprintln!("{loc}: ERROR: unknown keyword {name}", loc = key.loc, name = key.name);
-                return Err(())
+        }
+        "help" | "h" => {
+            for (signature, description) in DEBUGGER_COMMANDS {
+                println!("    {signature:30} {description}");
             }
         }
+        "quit" | "q" => return Ok(false),
+        name => {
+            lexer.diagnostics.report(Diagnostic::error(Code::DebuggerCommand, command.loc, format!("unknown command {name}. Type `help` for the list of commands")));
+        }
     }
-    Ok((sets, statements, runs))
+    Ok(true)
 }
 
-fn program_usage(program_name: &str) {
-    eprintln!("Usage: {program_name} <command> [ARGUMENTS]");
-    eprintln!("Commands:");
-    for command in COMMANDS.iter() {
-        let command_name = &command.name;
-        let command_signature = &command.signature;
-        let command_description = &command.description;
-        eprintln!("    {command_name} {command_signature} - {command_description}");
+/// Interactive debugging session reading commands from the standard input.
+fn debug_session(program: &Program, run: &Run) -> Result<()> {
+    let mut debugger = Debugger::new(program, run);
+    let interactive = io::stdin().is_terminal();
+    println!("{loc}: {kind}", loc = run.keyword.loc, kind = run.kind);
+    println!("{trace}", trace = debugger.machine.render_trace());
+    loop {
+        if interactive {
+            print!("(tula) ");
+            let _ = io::stdout().flush();
+        }
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).map_err(|err| {
+            eprintln!("ERROR: could not read the command: {err}");
+        })? == 0 {
+            break;
+        }
+        // The breakpoints keep referring to the symbols of the command they were created by
+        // for as long as the session lasts.
+        let line: &str = Box::leak(line.into_boxed_str());
+        // Errors in a command are already reported and should not end the session.
+        if let Ok(false) = debug_command(&mut debugger, line) {
+            break;
+        }
     }
-}
-
-fn command_usage(program_name: &str, command: &Command) {
-    let command_name = &command.name;
-    let command_signature = &command.signature;
-    eprintln!("Usage: {program_name} {command_name} {command_signature}");
+    Ok(())
 }
 
 struct Command {
     name: &'static str,
     description: &'static str,
     signature: &'static str,
-    run: fn (command: &Command, program_name: &str, args: env::Args) -> Result<()>,
+    run: fn (command: &Command, program_name: &str, args: env::Args, diagnostics: &mut Diagnostics<'static>) -> Result<ExitCode>,
 }
 
 const COMMANDS: &[Command] = &[
     Command {
         name: "run",
         description: "Run the Tula Program",
-        signature: "<input.tula>",
-        run: |command, program_name, mut args| {
-            let tula_path;
-            if let Some(path) = args.next() {
-                tula_path = path;
-            } else {
-                command_usage(program_name, command);
-                eprintln!("ERROR: no input.tula is provided");
-                return Err(());
+        signature: "[--max-steps <N>] [--timeout <SECS>] [--tape <input.tape>] [--trace-format=<text|json|jsonl>] [--message-format=<text|json>] [--warn-overlaps | --deny-overlaps] <input.tula>",
+        run: |command, program_name, mut args, diagnostics| {
+            let mut tula_path = None;
+            let mut tape_path = None;
+            let mut max_steps = None;
+            let mut timeout = None;
+            let mut overlaps = Overlaps::Allow;
+            let mut trace_format = TraceFormat::Text;
+
+            while let Some(arg) = args.next() {
+                if let Some(name) = arg.strip_prefix("--trace-format=") {
+                    trace_format = TraceFormat::from_name(name).ok_or_else(|| {
+                        usage_error(program_name, command, format!("unknown trace format {name}"), diagnostics);
+                    })?;
+                    continue;
+                }
+                if let Some(name) = arg.strip_prefix("--message-format=") {
+                    set_message_format(command, program_name, name, diagnostics)?;
+                    continue;
+                }
+                match arg.as_str() {
+                    "--warn-overlaps" => overlaps = Overlaps::Warn,
+                    "--deny-overlaps" => overlaps = Overlaps::Deny,
+                    "--max-steps" => {
+                        let Some(value) = args.next() else {
+                            usage_error(program_name, command, format!("no value is provided for {arg}"), diagnostics);
+                            return Err(())
+                        };
+                        max_steps = Some(value.parse::<usize>().map_err(|err| {
+                            usage_error(program_name, command, format!("{arg} expects a non-negative integer, but got {value}: {err}"), diagnostics);
+                        })?);
+                    }
+                    "--tape" => {
+                        let Some(value) = args.next() else {
+                            usage_error(program_name, command, format!("no value is provided for {arg}"), diagnostics);
+                            return Err(())
+                        };
+                        tape_path = Some(value);
+                    }
+                    "--timeout" => {
+                        let Some(value) = args.next() else {
+                            usage_error(program_name, command, format!("no value is provided for {arg}"), diagnostics);
+                            return Err(())
+                        };
+                        timeout = Some(value.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()).ok_or_else(|| {
+                            usage_error(program_name, command, format!("{arg} expects a non-negative amount of seconds, but got {value}"), diagnostics);
+                        })?);
+                    }
+                    _ => {
+                        if tula_path.is_some() {
+                            usage_error(program_name, command, "interpreting several files is not supported", diagnostics);
+                            return Err(())
+                        }
+                        tula_path = Some(arg)
+                    }
+                }
             }
-            let tula_source = fs::read_to_string(&tula_path).map_err(|err| {
-                eprintln!("ERROR: could not read file {tula_path}: {err}");
-            })?;
-            let (sets, statements, runs) = parse_program(&mut Lexer::new(&tula_source, &tula_path))?;
 
-            for statement in &statements {
-                statement.sanity_check()?
+            let Some(tula_path) = tula_path else {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(());
+            };
+            let mut program = parse_program(&tula_path, diagnostics)?;
+            if let Some(tape_path) = tape_path {
+                let mut lexer = Lexer::new(read_source(&tape_path, diagnostics)?, Box::leak(tape_path.into_boxed_str()));
+                lexer.diagnostics.format = diagnostics.format;
+                let tape = Tape::parse(&mut lexer);
+                diagnostics.extend(lexer.diagnostics);
+                let tape = tape?;
+                for run in &mut program.runs {
+                    run.tape = tape.clone();
+                }
             }
+            program.sanity_check(diagnostics)?;
+            check_overlaps(&program, overlaps, diagnostics)?;
+            program.report_dead_ends(diagnostics);
+            let dispatch = program.dispatch();
 
-            for run in &runs {
-                println!("{loc}: {kind}", loc = run.keyword.loc, kind = run.kind);
-
-                let mut machine = Machine {
-                    state: run.state.clone(),
-                    tape: run.tape.clone(),
-                    head: 0,
-                    halt: false,
-                };
+            let mut first_json_step = true;
+            if trace_format == TraceFormat::Json {
+                println!("[");
+            }
+            let mut all_halted = true;
+            for run in &program.runs {
+                if trace_format == TraceFormat::Text {
+                    println!("{loc}: {kind}", loc = run.keyword.loc, kind = run.kind);
+                }
 
-                while !machine.halt {
+                let start = Instant::now();
+                let mut machine = Machine::from_run(run);
+                loop {
+                    let mut json_step = None;
                     if run.kind == RunKind::Trace {
-                        machine.trace();
+                        match trace_format {
+                            TraceFormat::Text => println!("{trace}", trace = machine.render_trace()),
+                            TraceFormat::Json | TraceFormat::JsonLines => json_step = Some(start_json_event("step", run, &machine)),
+                        }
+                    }
+                    let mut limit = if max_steps.is_some_and(|max_steps| machine.steps >= max_steps) {
+                        Some("step limit")
+                    } else if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                        Some("timeout")
+                    } else {
+                        None
+                    };
+                    // The machine that halts right at the limit did halt
+                    if limit.is_some() && diagnostics.report_err(machine.clone().next(&dispatch))?.is_none() {
+                        limit = None;
+                    }
+                    let transition = if limit.is_some() {
+                        None
+                    } else {
+                        diagnostics.report_err(machine.next(&dispatch))?
+                    };
+                    if let (Some(mut json_step), Some(transition)) = (json_step, &transition) {
+                        let _ = write!(&mut json_step, ",\"case\":{case},\"write\":{write}}}",
+                                       case = json::Str(&transition.case.to_string()),
+                                       write = json::Str(&transition.write.to_string()));
+                        print_json_event(trace_format, &mut first_json_step, &json_step);
+                    }
+                    if transition.is_none() && trace_format != TraceFormat::Text {
+                        let mut json_result = start_json_event("result", run, &machine);
+                        let _ = write!(&mut json_result, ",\"halted\":{halted}}}", halted = limit.is_none());
+                        print_json_event(trace_format, &mut first_json_step, &json_result);
+                    }
+                    if let Some(limit) = limit {
+                        diagnostics.report(Diagnostic::error(Code::DidNotHalt, run.keyword.loc, format!("{kind} did not halt: reached {limit} after {steps} steps in state {state} with the head at {head}",
+                                                                                     kind = run.kind, steps = machine.steps, state = machine.state, head = machine.head)));
+                        all_halted = false;
+                        break;
+                    }
+                    match transition {
+                        Some(transition) => if transition.action == Action::Print {
+                            match trace_format {
+                                TraceFormat::Text => println!("{tape}", tape = machine.render_tape()),
+                                TraceFormat::Json | TraceFormat::JsonLines => {
+                                    let json_print = start_json_event("print", run, &machine) + "}";
+                                    print_json_event(trace_format, &mut first_json_step, &json_print);
+                                }
+                            }
+                        }
+                        None => break,
                     }
-                    machine.halt = true;
-                    machine.next(&statements, &sets)?;
                 }
             }
+            if trace_format == TraceFormat::Json {
+                if !first_json_step {
+                    println!();
+                }
+                println!("]");
+            }
 
-            Ok(())
+            if all_halted {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::from(EXIT_DID_NOT_HALT))
+            }
+        }
+    },
+    Command {
+        name: "debug",
+        description: "Step through a run of the Tula Program interactively",
+        signature: "[--run <N>] <input.tula>",
+        run: |command, program_name, mut args, diagnostics| {
+            let mut tula_path = None;
+            let mut run_number = 1;
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--run" => {
+                        let Some(value) = args.next() else {
+                            usage_error(program_name, command, format!("no value is provided for {arg}"), diagnostics);
+                            return Err(())
+                        };
+                        run_number = value.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| {
+                            usage_error(program_name, command, format!("{arg} expects a positive integer, but got {value}"), diagnostics);
+                        })?;
+                    }
+                    _ => {
+                        if tula_path.is_some() {
+                            usage_error(program_name, command, "debugging several files is not supported", diagnostics);
+                            return Err(())
+                        }
+                        tula_path = Some(arg)
+                    }
+                }
+            }
+
+            let Some(tula_path) = tula_path else {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(());
+            };
+            let program = parse_program(&tula_path, diagnostics)?;
+            program.sanity_check(diagnostics)?;
+            let Some(run) = program.runs.get(run_number - 1) else {
+                diagnostics.report(Diagnostic::global(Code::CommandLine, format!("{tula_path} has only {count} runs, but run number {run_number} was requested", count = program.runs.len())));
+                return Err(())
+            };
+            debug_session(&program, run)?;
+            Ok(ExitCode::SUCCESS)
+        }
+    },
+    Command {
+        name: "test",
+        description: "Run the tests of the Tula Programs",
+        signature: "<input.tula...>",
+        run: |command, program_name, args, diagnostics| {
+            let file_paths: Vec<String> = args.collect();
+            if file_paths.is_empty() {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(())
+            }
+            let mut passed = 0;
+            let mut failed = 0;
+            for file_path in &file_paths {
+                match run_tests(file_path, diagnostics) {
+                    Ok((file_passed, file_failed)) => {
+                        passed += file_passed;
+                        failed += file_failed;
+                    }
+                    Err(()) => {
+                        println!("{file_path}: FAILED: could not load the tests");
+                        failed += 1;
+                    }
+                }
+            }
+            println!("{passed} passed, {failed} failed");
+            if failed == 0 {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
         }
     },
     Command {
         name: "expand",
         description: "Expands all the Universal Quantifiers hardcoding all of the cases",
-        signature: "[--enum] <input.tula>",
-        run: |command, program_name: &str, args: env::Args| {
+        signature: "[--enum] [--message-format=<text|json>] [--warn-overlaps | --deny-overlaps] <input.tula>",
+        run: |command, program_name: &str, args: env::Args, diagnostics| {
             let mut source_path = None;
             let mut enumerate = false;
+            let mut overlaps = Overlaps::Allow;
 
             for arg in args {
+                if let Some(name) = arg.strip_prefix("--message-format=") {
+                    set_message_format(command, program_name, name, diagnostics)?;
+                    continue;
+                }
                 match arg.as_str() {
                     "--enum" => enumerate = true,
+                    "--warn-overlaps" => overlaps = Overlaps::Warn,
+                    "--deny-overlaps" => overlaps = Overlaps::Deny,
                     _ => {
                         if source_path.is_some() {
-                            command_usage(program_name, command);
-                            eprintln!("ERROR: interpreting several files is not supported");
+                            usage_error(program_name, command, "interpreting several files is not supported", diagnostics);
                             return Err(())
                         }
                         source_path = Some(arg)
@@ -663,42 +703,158 @@ const COMMANDS: &[Command] = &[
             }
 
             let Some(source_path) = source_path else {
-                command_usage(program_name, command);
-                eprintln!("ERROR: no input is provided");
+                usage_error(program_name, command, "no input is provided", diagnostics);
                 return Err(());
             };
 
-            let source = fs::read_to_string(&source_path).map_err(|err| {
-                eprintln!("ERROR: could not read file {source_path}: {err}");
-            })?;
-
-            let (sets, statements, runs) = parse_program(&mut Lexer::new(&source, &source_path))?;
+            let program = parse_program(&source_path, diagnostics)?;
             let mut cache = HashMap::new();
 
-            for statement in &statements {
-                statement.sanity_check()?;
-            }
-            for statement in statements.iter() {
-                statement.expand(&sets, &mut cache, enumerate)?;
+            program.sanity_check(diagnostics)?;
+            check_overlaps(&program, overlaps, diagnostics)?;
+            for statement in &program.statements {
+                for statement in diagnostics.report_err(statement.expand(&program.sets))? {
+                    print_expanded(&statement, &mut cache, enumerate);
+                }
             }
-            for run in &runs {
-                run.expand(&mut cache, enumerate);
+            for run in &program.runs {
+                print_run(run, &mut cache, enumerate);
             }
             if enumerate {
                 let mut table: Vec<_> = cache.iter().collect();
-                table.sort_by(|(_, a), (_, b)| a.cmp(b));
+                table.sort_by_key(|(_, id)| **id);
                 for (expr, id) in &table {
                     println!("// {id} = {expr}");
                 }
             }
-            Ok(())
+            Ok(ExitCode::SUCCESS)
+        },
+    },
+    Command {
+        name: "check",
+        description: "Check the Tula Program for errors without running it. Overlapping cases are reported as warnings by default",
+        signature: "[--message-format=<text|json>] [--allow-overlaps | --deny-overlaps] <input.tula>",
+        run: |command, program_name, args, diagnostics| {
+            let mut tula_path = None;
+            let mut overlaps = Overlaps::Warn;
+
+            for arg in args {
+                if let Some(name) = arg.strip_prefix("--message-format=") {
+                    set_message_format(command, program_name, name, diagnostics)?;
+                    continue;
+                }
+                match arg.as_str() {
+                    "--allow-overlaps" => overlaps = Overlaps::Allow,
+                    "--deny-overlaps" => overlaps = Overlaps::Deny,
+                    _ => {
+                        if tula_path.is_some() {
+                            usage_error(program_name, command, "checking several files is not supported", diagnostics);
+                            return Err(())
+                        }
+                        tula_path = Some(arg)
+                    }
+                }
+            }
+
+            let Some(tula_path) = tula_path else {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(());
+            };
+            let program = parse_program(&tula_path, diagnostics)?;
+            program.sanity_check(diagnostics)?;
+            check_overlaps(&program, overlaps, diagnostics)?;
+            program.report_dead_ends(diagnostics);
+            Ok(ExitCode::SUCCESS)
+        }
+    },
+    Command {
+        name: "fmt",
+        description: "Print the Tula Program in the canonical layout. With --check only verify that the file is already formatted",
+        signature: "[--check] <input.tula>",
+        run: |command, program_name, args, diagnostics| {
+            let mut tula_path = None;
+            let mut check = false;
+
+            for arg in args {
+                match arg.as_str() {
+                    "--check" => check = true,
+                    _ => {
+                        if tula_path.is_some() {
+                            usage_error(program_name, command, "formatting several files is not supported", diagnostics);
+                            return Err(())
+                        }
+                        tula_path = Some(arg)
+                    }
+                }
+            }
+
+            let Some(tula_path) = tula_path else {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(());
+            };
+            let source = read_source(&tula_path, diagnostics)?;
+            let file_path: &'static str = Box::leak(tula_path.into());
+            let program = parse_program_source(source, file_path, diagnostics)?;
+            let formatted = tula::formatter::format_source(source, file_path, &program);
+            if !check {
+                print!("{formatted}");
+                return Ok(ExitCode::SUCCESS)
+            }
+            let mut expected_lines = formatted.lines();
+            let mut actual_lines = source.lines();
+            for row in 1.. {
+                let (expected, actual) = (expected_lines.next(), actual_lines.next());
+                if expected == actual && expected.is_none() {
+                    break
+                }
+                if expected != actual {
+                    eprintln!("{file_path}:{row}: the file is not formatted");
+                    eprintln!("    expected: {expected}", expected = expected.unwrap_or("<end of file>"));
+                    eprintln!("    actual:   {actual}", actual = actual.unwrap_or("<end of file>"));
+                    return Ok(ExitCode::FAILURE)
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+    },
+    Command {
+        name: "graph",
+        description: "Print the state-transition graph of the Tula Program in the Graphviz DOT format",
+        signature: "<input.tula>",
+        run: |command, program_name, mut args, diagnostics| {
+            let Some(tula_path) = args.next() else {
+                usage_error(program_name, command, "no input.tula is provided", diagnostics);
+                return Err(());
+            };
+            if let Some(arg) = args.next() {
+                usage_error(program_name, command, format!("unexpected argument {arg}"), diagnostics);
+                return Err(());
+            }
+            let program = parse_program(&tula_path, diagnostics)?;
+            program.sanity_check(diagnostics)?;
+            let graph = diagnostics.report_err(Graph::new(&program))?;
+            print!("{graph}");
+            Ok(ExitCode::SUCCESS)
+        }
+    },
+    Command {
+        name: "lsp",
+        description: "Start the Language Server speaking LSP over the standard input and output",
+        signature: "",
+        run: |command, program_name, mut args, diagnostics| {
+            if let Some(arg) = args.next() {
+                usage_error(program_name, command, format!("unexpected argument {arg}"), diagnostics);
+                return Err(());
+            }
+            tula::lsp::serve(io::stdin().lock(), io::stdout().lock())?;
+            Ok(ExitCode::SUCCESS)
         },
     },
     Command {
         name: "lex",
         description: "Lex the given file to see how the Lexer behaves",
         signature: "<input.tula>",
-        run: |command, program_name, mut args| {
+        run: |command, program_name, mut args, diagnostics| {
             let input_path;
             if let Some(arg) = args.next() {
                 input_path = arg;
@@ -707,61 +863,83 @@ const COMMANDS: &[Command] = &[
                 return Err(());
             }
 
-            let input_source = fs::read_to_string(&input_path).map_err(|err| {
-                eprintln!("ERROR: could not read file {input_path}: {err}");
-            })?;
+            let input_source = read_source(&input_path, diagnostics)?;
 
-            for Symbol{loc, name} in Lexer::new(&input_source, &input_path) {
+            for Symbol{loc, name} in Lexer::new(input_source, &input_path) {
                 println!("{loc}: {name}");
             }
-            Ok(())
+            Ok(ExitCode::SUCCESS)
         },
     },
+    Command {
+        name: "explain",
+        description: "Explains the error code of a diagnostic",
+        signature: "<code>",
+        run: |command, program_name, mut args, diagnostics| {
+            let Some(id) = args.next() else {
+                usage_error(program_name, command, "no error code is provided", diagnostics);
+                return Err(())
+            };
+            let Some(code) = Code::from_id(&id) else {
+                diagnostics.report(Diagnostic::global(Code::CommandLine, format!("unknown error code {id}")));
+                return Err(())
+            };
+            println!("{code}: {explanation}", explanation = code.explanation());
+            Ok(ExitCode::SUCCESS)
+        }
+    },
     Command {
         name: "help",
         description: "Prints help about commands",
         signature: "[command]",
-        run: |_command, program_name: &str, mut args: env::Args| {
+        run: |_command, program_name: &str, mut args: env::Args, diagnostics| {
             if let Some(command_name) = args.next() {
                 if let Some(command) = COMMANDS.iter().find(|command| command.name == command_name) {
                     command_usage(program_name, command)
                 } else {
                     program_usage(program_name);
-                    eprintln!("ERROR: unknown command {command_name}");
+                    diagnostics.report(Diagnostic::global(Code::CommandLine, format!("unknown command {command_name}")));
                     return Err(())
                 }
             } else {
                 program_usage(program_name)
             }
-            Ok(())
+            Ok(ExitCode::SUCCESS)
         }
     }
 ];
 
-fn start() -> Result<()> {
+fn start() -> Result<ExitCode> {
     let mut args = env::args();
     let program_name = args.next().expect("Program name is alway present");
 
-    let command_name;
-    if let Some(arg) = args.next() {
-        command_name = arg;
-    } else {
-        program_usage(&program_name);
-        eprintln!("ERROR: no command is provided");
-        return Err(())
+    let mut diagnostics = Diagnostics::default();
+    // The commands parse the format along with the rest of their flags, but the problems
+    // found before that are expected to be reported in it too
+    if let Some(format) = env::args().filter_map(|arg| arg.strip_prefix("--message-format=").and_then(MessageFormat::from_name)).next_back() {
+        diagnostics.format = format;
     }
-
-    if let Some(command) = COMMANDS.iter().find(|command| command.name == command_name) {
-        (command.run)(command, &program_name, args)
+    let result = if let Some(command_name) = args.next() {
+        if let Some(command) = COMMANDS.iter().find(|command| command.name == command_name) {
+            (command.run)(command, &program_name, args, &mut diagnostics)
+        } else {
+            diagnostics.report(Diagnostic::global(Code::CommandLine, format!("no command with the name {command_name}")));
+            Err(())
+        }
     } else {
-        eprintln!("ERROR: no command with the name {command_name}");
+        program_usage(&program_name);
+        diagnostics.report(Diagnostic::global(Code::CommandLine, "no command is provided"));
         Err(())
+    };
+    if let Some(summary) = diagnostics.summary() {
+        eprintln!("{summary}");
     }
+    result
 }
 
 fn main() -> ExitCode {
     match start() {
-        Ok(()) => ExitCode::SUCCESS,
+        Ok(code) => code,
         Err(()) => ExitCode::FAILURE,
     }
 }